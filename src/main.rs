@@ -1,20 +1,33 @@
-use std::env;
 #[cfg(unix)]
 use std::collections::BTreeSet;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+#[cfg(unix)]
+use std::ffi::{CStr, CString};
 use std::fs;
 use std::fs::OpenOptions;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Read, Write};
+use std::iter::Peekable;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, ExitStatus};
+use std::str::Chars;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
 
 #[cfg(unix)]
 use libc::{self, STDIN_FILENO};
 #[cfg(unix)]
-use std::io::Read;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 
 const PROMPT: &str = "$ ";
+const CONTINUATION_PROMPT: &str = "> ";
+const STATUS_NOT_EXECUTABLE: i32 = 126;
+const STATUS_NOT_FOUND: i32 = 127;
+const STATUS_BROKEN_PIPE: i32 = 141;
 #[cfg(unix)]
 const COMPLETION_BUILTINS: [&str; 2] = ["echo", "exit"];
 
@@ -34,7 +47,7 @@ fn is_executable(path: &Path) -> bool {
 }
 
 #[cfg(unix)]
-fn completion_matches(prefix: &str) -> Vec<String> {
+fn completion_matches(prefix: &str, commands: &mut CommandTable) -> Vec<String> {
     let mut matches = BTreeSet::new();
 
     for builtin in COMPLETION_BUILTINS {
@@ -43,26 +56,9 @@ fn completion_matches(prefix: &str) -> Vec<String> {
         }
     }
 
-    if let Some(path_var) = env::var_os("PATH") {
-        for dir in env::split_paths(&path_var) {
-            let Ok(entries) = fs::read_dir(dir) else {
-                continue;
-            };
-
-            for entry in entries.flatten() {
-                let path = entry.path();
-                if !is_executable(&path) {
-                    continue;
-                }
-
-                let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
-                    continue;
-                };
-
-                if name.starts_with(prefix) {
-                    matches.insert(name.to_string());
-                }
-            }
+    for name in commands.names() {
+        if name.starts_with(prefix) {
+            matches.insert(name.clone());
         }
     }
 
@@ -115,6 +111,7 @@ fn redraw_input_line(buffer: &str, shown_len: &mut usize) {
 struct RawModeGuard {
     fd: i32,
     original: libc::termios,
+    raw: libc::termios,
 }
 
 #[cfg(unix)]
@@ -128,7 +125,9 @@ impl RawModeGuard {
             }
 
             let mut raw = original;
-            raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+            // Without ISIG, Ctrl-C reaches the line editor as a byte instead
+            // of interrupting the shell.
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
             raw.c_iflag &= !(libc::ICRNL | libc::IXON);
             raw.c_cc[libc::VMIN] = 1;
             raw.c_cc[libc::VTIME] = 0;
@@ -137,7 +136,7 @@ impl RawModeGuard {
                 return Err(io::Error::last_os_error());
             }
 
-            Ok(Self { fd, original })
+            Ok(Self { fd, original, raw })
         }
     }
 }
@@ -152,8 +151,84 @@ impl Drop for RawModeGuard {
     }
 }
 
+/// Signals an interactive shell ignores so that only the foreground job gets
+/// them; child processes are reset to the default dispositions.
+#[cfg(unix)]
+const JOB_CONTROL_SIGNALS: [libc::c_int; 5] = [
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+];
+
+/// Terminal ownership for an interactive shell: its own process group and
+/// the terminal modes the shell and its jobs each expect.
+#[cfg(unix)]
+#[derive(Clone, Copy)]
+struct JobControl {
+    fd: i32,
+    shell_pgid: libc::pid_t,
+    shell_modes: libc::termios,
+    job_modes: libc::termios,
+}
+
+#[cfg(unix)]
+impl JobControl {
+    /// Moves the shell into its own process group in the foreground of the
+    /// terminal and makes it ignore the job control signals.
+    fn new(guard: &RawModeGuard) -> Self {
+        // SAFETY: these calls only change the dispositions and process group
+        // of this process and the foreground group of a valid tty fd.
+        unsafe {
+            for signal in JOB_CONTROL_SIGNALS {
+                libc::signal(signal, libc::SIG_IGN);
+            }
+            libc::setpgid(0, 0);
+            let shell_pgid = libc::getpgrp();
+            libc::tcsetpgrp(guard.fd, shell_pgid);
+            Self {
+                fd: guard.fd,
+                shell_pgid,
+                shell_modes: guard.raw,
+                job_modes: guard.original,
+            }
+        }
+    }
+
+    /// Restores the cooked terminal modes programs expect before a
+    /// foreground job starts.
+    fn prepare_terminal(&self) {
+        // SAFETY: `job_modes` was captured from this fd by tcgetattr.
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSADRAIN, &self.job_modes);
+        }
+    }
+
+    fn give_terminal(&self, pgid: libc::pid_t) {
+        // SAFETY: tcsetpgrp only changes the foreground group of the tty.
+        unsafe {
+            libc::tcsetpgrp(self.fd, pgid);
+        }
+    }
+
+    /// Takes the terminal back once the foreground job is done and puts it
+    /// into the shell's raw mode again.
+    fn reclaim_terminal(&self) {
+        // SAFETY: as above; `shell_modes` came from the same fd.
+        unsafe {
+            libc::tcsetpgrp(self.fd, self.shell_pgid);
+            libc::tcsetattr(self.fd, libc::TCSADRAIN, &self.shell_modes);
+        }
+    }
+}
+
 #[cfg(unix)]
-fn complete_buffer(buffer: &mut String, pending_multi: &mut Option<String>) {
+fn complete_buffer(
+    buffer: &mut String,
+    pending_multi: &mut Option<String>,
+    commands: &mut CommandTable,
+) {
     if buffer.chars().any(char::is_whitespace) {
         ring_bell();
         *pending_multi = None;
@@ -161,7 +236,7 @@ fn complete_buffer(buffer: &mut String, pending_multi: &mut Option<String>) {
     }
 
     let prefix = buffer.clone();
-    let matches = completion_matches(&prefix);
+    let matches = completion_matches(&prefix, commands);
     if matches.is_empty() {
         ring_bell();
         *pending_multi = None;
@@ -199,7 +274,7 @@ fn complete_buffer(buffer: &mut String, pending_multi: &mut Option<String>) {
 }
 
 #[cfg(unix)]
-fn read_user_input(history: &[String]) -> io::Result<Option<String>> {
+fn read_user_input(history: &[String], commands: &mut CommandTable) -> io::Result<Option<String>> {
     let mut input = String::new();
     let mut pending_multi = None;
     let mut history_cursor: Option<usize> = None;
@@ -221,7 +296,7 @@ fn read_user_input(history: &[String]) -> io::Result<Option<String>> {
                 return Ok(Some(input));
             }
             b'\t' => {
-                complete_buffer(&mut input, &mut pending_multi);
+                complete_buffer(&mut input, &mut pending_multi, commands);
                 shown_len = input.len();
                 history_cursor = None;
             }
@@ -282,12 +357,17 @@ fn read_user_input(history: &[String]) -> io::Result<Option<String>> {
                     _ => {}
                 }
             }
-            4 => {
-                if input.is_empty() {
-                    print!("\r\n");
-                    let _ = io::stdout().flush();
-                    return Ok(None);
-                }
+            4 if input.is_empty() => {
+                print!("\r\n");
+                let _ = io::stdout().flush();
+                return Ok(None);
+            }
+            3 => {
+                // Ctrl-C abandons the line along with any completion or
+                // history browsing state.
+                print!("^C\r\n");
+                let _ = io::stdout().flush();
+                return Err(io::ErrorKind::Interrupted.into());
             }
             ch if ch.is_ascii_graphic() || ch == b' ' => {
                 let c = ch as char;
@@ -304,7 +384,10 @@ fn read_user_input(history: &[String]) -> io::Result<Option<String>> {
 }
 
 #[cfg(not(unix))]
-fn read_user_input(_history: &[String]) -> io::Result<Option<String>> {
+fn read_user_input(
+    _history: &[String],
+    _commands: &mut CommandTable,
+) -> io::Result<Option<String>> {
     let mut input = String::new();
     let bytes = io::stdin().read_line(&mut input)?;
     if bytes == 0 {
@@ -313,9 +396,14 @@ fn read_user_input(_history: &[String]) -> io::Result<Option<String>> {
     Ok(Some(input.trim_end_matches(['\r', '\n']).to_string()))
 }
 
-fn find_in_path(cmd: &str) -> Option<PathBuf> {
-    let path_var = env::var_os("PATH")?;
-    for dir in env::split_paths(&path_var) {
+/// Finds the executable `cmd` names. A name containing `/` is a path in its
+/// own right and is not looked up in `PATH`.
+fn find_in_path(cmd: &str, path_var: Option<&str>) -> Option<PathBuf> {
+    if cmd.contains('/') {
+        let path = PathBuf::from(cmd);
+        return is_executable(&path).then_some(path);
+    }
+    for dir in env::split_paths(path_var?) {
         let candidate = dir.join(cmd);
         if is_executable(&candidate) {
             return Some(candidate);
@@ -324,13 +412,399 @@ fn find_in_path(cmd: &str) -> Option<PathBuf> {
     None
 }
 
+/// Where commands were found in `PATH`, with how often each was run, and the
+/// executable names completion offers. Both belong to one value of `PATH`
+/// and are forgotten when it changes.
+#[derive(Clone, Default)]
+struct CommandTable {
+    path: Option<String>,
+    hashed: BTreeMap<String, (PathBuf, usize)>,
+    #[cfg(unix)]
+    names: Option<BTreeSet<String>>,
+}
+
+impl CommandTable {
+    fn set_path(&mut self, path: Option<&str>) {
+        if self.path.as_deref() != path {
+            *self = CommandTable {
+                path: path.map(str::to_string),
+                ..CommandTable::default()
+            };
+        }
+    }
+
+    /// Clears everything remembered for the current `PATH`.
+    fn forget(&mut self) {
+        let path = self.path.take();
+        *self = CommandTable {
+            path,
+            ..CommandTable::default()
+        };
+    }
+
+    /// Finds `cmd`, searching `PATH` only if it is not remembered or the
+    /// remembered file has gone away.
+    fn find(&mut self, cmd: &str) -> Option<PathBuf> {
+        if let Some((path, hits)) = self.hashed.get_mut(cmd)
+            && is_executable(path)
+        {
+            *hits += 1;
+            return Some(path.clone());
+        }
+        let path = find_in_path(cmd, self.path.as_deref())?;
+        self.hashed.insert(cmd.to_string(), (path.clone(), 1));
+        Some(path)
+    }
+
+    /// Every executable name in `PATH`, read from the directories once.
+    #[cfg(unix)]
+    fn names(&mut self) -> &BTreeSet<String> {
+        let path = self.path.as_deref();
+        self.names.get_or_insert_with(|| {
+            let mut names = BTreeSet::new();
+            for dir in path.map(env::split_paths).into_iter().flatten() {
+                let Ok(entries) = fs::read_dir(dir) else {
+                    continue;
+                };
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    if is_executable(&path)
+                        && let Some(name) = path.file_name().and_then(|n| n.to_str())
+                    {
+                        names.insert(name.to_string());
+                    }
+                }
+            }
+            names
+        })
+    }
+}
+
+/// Finds the file `source` reads: a name containing `/` is used as given,
+/// others are looked for in `PATH` and then in the current directory.
+fn find_source_file(name: &str, path_var: Option<&str>) -> PathBuf {
+    if !name.contains('/')
+        && let Some(path) = path_var
+            .into_iter()
+            .flat_map(env::split_paths)
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    {
+        return path;
+    }
+    PathBuf::from(name)
+}
+
+#[derive(Clone)]
+enum WordPart {
+    Text { text: String, quoted: bool },
+    Param { expr: String, quoted: bool },
+    Command { source: String, quoted: bool },
+}
+
 #[derive(Clone)]
 struct ParsedToken {
+    text: String,
+    operator: bool,
+    parts: Vec<WordPart>,
+}
+
+impl ParsedToken {
+    fn operator(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            operator: true,
+            parts: Vec::new(),
+        }
+    }
+
+    fn literal(text: String) -> Self {
+        Self {
+            parts: vec![WordPart::Text {
+                text: text.clone(),
+                quoted: true,
+            }],
+            text,
+            operator: false,
+        }
+    }
+
+    /// Builds the pathname pattern for an expanded word, escaping quoted
+    /// text. Returns `None` when no unquoted glob character is present.
+    fn glob_pattern(&self) -> Option<String> {
+        let mut pattern = String::new();
+        let mut has_meta = false;
+        for part in &self.parts {
+            if let WordPart::Text { text, quoted } = part {
+                if *quoted {
+                    pattern.push_str(&escape_glob(text));
+                } else {
+                    has_meta |= text.contains(['*', '?', '[']);
+                    pattern.push_str(text);
+                }
+            }
+        }
+        has_meta.then_some(pattern)
+    }
+
+    fn is_operator(&self, op: &str) -> bool {
+        self.operator && self.text == op
+    }
+}
+
+#[derive(Default)]
+struct WordBuilder {
     text: String,
     quoted: bool,
+    parts: Vec<WordPart>,
+}
+
+impl WordBuilder {
+    fn push(&mut self, ch: char, quoted: bool) {
+        self.text.push(ch);
+        self.quoted |= quoted;
+        if let Some(WordPart::Text {
+            text,
+            quoted: last_quoted,
+        }) = self.parts.last_mut()
+            && *last_quoted == quoted
+        {
+            text.push(ch);
+            return;
+        }
+        self.parts.push(WordPart::Text {
+            text: ch.to_string(),
+            quoted,
+        });
+    }
+
+    fn push_param(&mut self, expr: String, raw: &str, quoted: bool) {
+        self.text.push_str(raw);
+        self.quoted |= quoted;
+        self.parts.push(WordPart::Param { expr, quoted });
+    }
+
+    fn push_command(&mut self, source: String, raw: &str, quoted: bool) {
+        self.text.push_str(raw);
+        self.quoted |= quoted;
+        self.parts.push(WordPart::Command { source, quoted });
+    }
+
+    /// Reads the body of a `$(...)` substitution up to its matching `)`,
+    /// skipping over quoted text and nested parentheses.
+    fn push_command_substitution(&mut self, chars: &mut Peekable<Chars<'_>>, quoted: bool) {
+        let mut source = String::new();
+        let mut depth = 0;
+        let mut in_single = false;
+        let mut in_double = false;
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' if !in_single => {
+                    source.push(ch);
+                    if let Some(next) = chars.next() {
+                        source.push(next);
+                    }
+                    continue;
+                }
+                '\'' if !in_double => in_single = !in_single,
+                '"' if !in_single => in_double = !in_double,
+                _ if in_single || in_double => {}
+                '(' => depth += 1,
+                ')' if depth == 0 => {
+                    let raw = format!("$({source})");
+                    self.push_command(source, &raw, quoted);
+                    return;
+                }
+                ')' => depth -= 1,
+                _ => {}
+            }
+            source.push(ch);
+        }
+        for ch in format!("$({source}").chars() {
+            self.push(ch, quoted);
+        }
+    }
+
+    /// Reads a legacy `` `...` `` substitution, where a backslash only
+    /// escapes `$`, `` ` `` and `\\`.
+    fn push_backquote(&mut self, chars: &mut Peekable<Chars<'_>>, quoted: bool) {
+        let mut source = String::new();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '`' => {
+                    let raw = format!("`{source}`");
+                    self.push_command(source, &raw, quoted);
+                    return;
+                }
+                '\\' => match chars.peek().copied() {
+                    Some(next @ ('$' | '`' | '\\')) => {
+                        chars.next();
+                        source.push(next);
+                    }
+                    _ => source.push(ch),
+                },
+                _ => source.push(ch),
+            }
+        }
+        for ch in format!("`{source}").chars() {
+            self.push(ch, quoted);
+        }
+    }
+
+    /// Records an opening quote, so that `''` and `""` still form a word.
+    fn open_quote(&mut self) {
+        self.quoted = true;
+    }
+
+    /// Takes the current word if it is an unquoted file descriptor number
+    /// directly preceding a redirection operator.
+    fn take_fd_prefix(&mut self) -> String {
+        if self.quoted || self.text.is_empty() || !self.text.chars().all(|c| c.is_ascii_digit()) {
+            return String::new();
+        }
+        std::mem::take(self).text
+    }
+
+    fn into_token(self) -> ParsedToken {
+        ParsedToken {
+            text: self.text,
+            operator: false,
+            parts: self.parts,
+        }
+    }
+
+    fn finish(&mut self, tokens: &mut Vec<ParsedToken>) {
+        let mut word = std::mem::take(self);
+        // A word made only of empty quotes expands to one empty field; a
+        // quoted `"$@"` keeps its parts so it can still expand to none.
+        if word.parts.is_empty() && word.quoted {
+            word.parts.push(WordPart::Text {
+                text: String::new(),
+                quoted: true,
+            });
+        }
+        if !word.parts.is_empty() {
+            tokens.push(word.into_token());
+        }
+    }
+
+    fn push_dollar(&mut self, chars: &mut Peekable<Chars<'_>>, quoted: bool) {
+        match chars.peek().copied() {
+            Some('(') => {
+                chars.next();
+                self.push_command_substitution(chars, quoted);
+            }
+            Some('{') => {
+                chars.next();
+                let mut expr = String::new();
+                let mut depth = 0;
+                let mut in_single = false;
+                let mut in_double = false;
+                while let Some(ch) = chars.next() {
+                    match ch {
+                        '\\' if !in_single => {
+                            expr.push(ch);
+                            if let Some(next) = chars.next() {
+                                expr.push(next);
+                            }
+                            continue;
+                        }
+                        '\'' if !in_double => in_single = !in_single,
+                        '"' if !in_single => in_double = !in_double,
+                        _ if in_single || in_double => {}
+                        '{' => depth += 1,
+                        '}' if depth == 0 => {
+                            let raw = format!("${{{expr}}}");
+                            self.push_param(expr, &raw, quoted);
+                            return;
+                        }
+                        '}' => depth -= 1,
+                        _ => {}
+                    }
+                    expr.push(ch);
+                }
+                for ch in format!("${{{expr}").chars() {
+                    self.push(ch, quoted);
+                }
+            }
+            Some(ch) if ch.is_ascii_alphabetic() || ch == '_' => {
+                let mut name = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if !(ch.is_ascii_alphanumeric() || ch == '_') {
+                        break;
+                    }
+                    name.push(ch);
+                    chars.next();
+                }
+                let raw = format!("${name}");
+                self.push_param(name, &raw, quoted);
+            }
+            Some(ch) if ch.is_ascii_digit() || matches!(ch, '?' | '$' | '!' | '#' | '@' | '*') => {
+                chars.next();
+                self.push_param(ch.to_string(), &format!("${ch}"), quoted);
+            }
+            _ => self.push('$', quoted),
+        }
+    }
+}
+
+/// Accumulates expanded words, splitting unquoted expansion results on `IFS`.
+#[derive(Default)]
+struct FieldBuilder {
+    fields: Vec<ParsedToken>,
+    current: WordBuilder,
+    started: bool,
+}
+
+impl FieldBuilder {
+    fn push_text(&mut self, text: &str, quoted: bool) {
+        for ch in text.chars() {
+            self.current.push(ch, quoted);
+        }
+        self.started = true;
+    }
+
+    fn push_split(&mut self, text: &str, ifs: &str) {
+        for ch in text.chars() {
+            if !ifs.contains(ch) {
+                self.current.push(ch, false);
+                self.started = true;
+            } else if ch.is_whitespace() {
+                self.end_field();
+            } else {
+                self.started = true;
+                self.end_field();
+            }
+        }
+    }
+
+    fn end_field(&mut self) {
+        if self.started {
+            let word = std::mem::take(&mut self.current);
+            self.fields.push(word.into_token());
+        }
+        self.started = false;
+    }
+
+    fn finish(mut self) -> Vec<ParsedToken> {
+        self.end_field();
+        self.fields
+    }
 }
 
 fn parse_line(input: &str) -> Vec<ParsedToken> {
+    tokenize(input, true)
+}
+
+/// Parses a single word in which blanks and operator characters are literal,
+/// as used for the operands of `${NAME-word}` style expansions.
+fn parse_word(input: &str) -> ParsedToken {
+    tokenize(input, false)
+        .pop()
+        .unwrap_or_else(|| WordBuilder::default().into_token())
+}
+
+fn tokenize(input: &str, split_words: bool) -> Vec<ParsedToken> {
     #[derive(Copy, Clone)]
     enum State {
         Normal,
@@ -339,683 +813,4632 @@ fn parse_line(input: &str) -> Vec<ParsedToken> {
     }
 
     let mut args = Vec::new();
-    let mut current = String::new();
-    let mut current_quoted = false;
+    let mut current = WordBuilder::default();
     let mut state = State::Normal;
     let mut chars = input.chars().peekable();
 
     while let Some(ch) = chars.next() {
         match state {
             State::Normal => match ch {
-                '\'' => state = State::Single,
-                '"' => state = State::Double,
+                _ if !split_words && !matches!(ch, '\'' | '"' | '\\' | '$' | '`') => {
+                    current.push(ch, false);
+                }
+                '\'' => {
+                    current.open_quote();
+                    state = State::Single;
+                }
+                '"' => {
+                    current.open_quote();
+                    state = State::Double;
+                }
                 '\\' => {
                     if let Some(next) = chars.next() {
-                        current.push(next);
-                        current_quoted = true;
+                        current.push(next, true);
                     } else {
-                        current.push('\\');
-                        current_quoted = true;
+                        current.push('\\', true);
                     }
                 }
-                c if c.is_whitespace() => {
-                    if !current.is_empty() {
-                        args.push(ParsedToken {
-                            text: current.clone(),
-                            quoted: current_quoted,
-                        });
-                        current.clear();
-                        current_quoted = false;
+                '$' => current.push_dollar(&mut chars, false),
+                '`' => current.push_backquote(&mut chars, false),
+                '|' | ';' => {
+                    current.finish(&mut args);
+                    let op = if ch == '|' && chars.peek() == Some(&'|') {
+                        chars.next();
+                        "||"
+                    } else if ch == '|' && chars.peek() == Some(&'&') {
+                        chars.next();
+                        "|&"
+                    } else if ch == '|' {
+                        "|"
+                    } else {
+                        ";"
+                    };
+                    args.push(ParsedToken::operator(op));
+                }
+                '&' if chars.peek() == Some(&'&') => {
+                    chars.next();
+                    current.finish(&mut args);
+                    args.push(ParsedToken::operator("&&"));
+                }
+                '&' if chars.peek() == Some(&'>') => {
+                    chars.next();
+                    current.finish(&mut args);
+                    let op = if chars.peek() == Some(&'>') {
+                        chars.next();
+                        "&>>"
+                    } else {
+                        "&>"
+                    };
+                    args.push(ParsedToken::operator(op));
+                }
+                '&' => {
+                    current.finish(&mut args);
+                    args.push(ParsedToken::operator("&"));
+                }
+                '>' => {
+                    let mut op = current.take_fd_prefix();
+                    current.finish(&mut args);
+                    op.push('>');
+                    if let Some(&next @ ('>' | '&' | '|')) = chars.peek() {
+                        chars.next();
+                        op.push(next);
+                    }
+                    args.push(ParsedToken::operator(&op));
+                }
+                '<' => {
+                    let mut op = current.take_fd_prefix();
+                    current.finish(&mut args);
+                    op.push('<');
+                    match chars.peek().copied() {
+                        Some('<') => {
+                            chars.next();
+                            op.push('<');
+                            if let Some(&next @ ('<' | '-')) = chars.peek() {
+                                chars.next();
+                                op.push(next);
+                            }
+                        }
+                        Some(next @ ('&' | '>')) => {
+                            chars.next();
+                            op.push(next);
+                        }
+                        _ => {}
                     }
+                    args.push(ParsedToken::operator(&op));
                 }
-                _ => current.push(ch),
+                c if c.is_whitespace() => current.finish(&mut args),
+                // A `#` starting a word comments out the rest of the line.
+                '#' if current.parts.is_empty() && !current.quoted => break,
+                _ => current.push(ch, false),
             },
             State::Single => {
                 if ch == '\'' {
                     state = State::Normal;
                 } else {
-                    current.push(ch);
-                    current_quoted = true;
+                    current.push(ch, true);
                 }
             }
             State::Double => match ch {
                 '"' => state = State::Normal,
                 '\\' => {
                     if let Some(next) = chars.peek().copied() {
-                        if next == '"' || next == '\\' {
+                        if matches!(next, '"' | '\\' | '$' | '`') {
                             chars.next();
-                            current.push(next);
-                            current_quoted = true;
+                            current.push(next, true);
                         } else {
-                            current.push('\\');
-                            current_quoted = true;
+                            current.push('\\', true);
                         }
                     } else {
-                        current.push('\\');
-                        current_quoted = true;
+                        current.push('\\', true);
                     }
                 }
-                _ => {
-                    current.push(ch);
-                    current_quoted = true;
-                }
+                '$' if chars.peek() == Some(&'"') => current.push('$', true),
+                '$' => current.push_dollar(&mut chars, true),
+                '`' => current.push_backquote(&mut chars, true),
+                _ => current.push(ch, true),
             },
         }
     }
 
-    if !current.is_empty() {
-        args.push(ParsedToken {
-            text: current,
-            quoted: current_quoted,
-        });
-    }
-
+    current.finish(&mut args);
     args
 }
 
-#[derive(Copy, Clone)]
-enum RedirectMode {
-    Truncate,
-    Append,
-}
-
-#[derive(Default, Clone)]
-struct RedirectSpec {
-    stdout: Option<(PathBuf, RedirectMode)>,
-    stderr: Option<(PathBuf, RedirectMode)>,
-}
-
-#[derive(Clone)]
-struct PipelineStage {
-    cmd: String,
-    args: Vec<String>,
-    redirects: RedirectSpec,
-}
-
-fn parse_redirections(tokens: Vec<ParsedToken>) -> (Vec<String>, RedirectSpec) {
-    let mut args = Vec::new();
-    let mut redirects = RedirectSpec::default();
-    let mut i = 0;
-
-    while i < tokens.len() {
-        let token = &tokens[i];
-
-        let parse_op = |s: &str| -> Option<(bool, RedirectMode, String)> {
-            let ops = [
-                ("1>>", true, RedirectMode::Append),
-                ("2>>", false, RedirectMode::Append),
-                (">>", true, RedirectMode::Append),
-                ("1>", true, RedirectMode::Truncate),
-                ("2>", false, RedirectMode::Truncate),
-                (">", true, RedirectMode::Truncate),
-            ];
+/// Builds the word holding a here-document body. The whole body behaves as if
+/// double-quoted, except that `"` is literal; with a quoted delimiter no
+/// expansion happens at all.
+fn parse_heredoc_body(body: &str, expand: bool) -> ParsedToken {
+    if !expand {
+        return ParsedToken::literal(body.to_string());
+    }
 
-            for (op, is_stdout, mode) in ops {
-                if s == op {
-                    return Some((is_stdout, mode, String::new()));
+    let mut word = WordBuilder::default();
+    let mut chars = body.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.peek().copied() {
+                Some('\n') => {
+                    chars.next();
                 }
-                if s.starts_with(op) && s.len() > op.len() {
-                    return Some((is_stdout, mode, s[op.len()..].to_string()));
+                Some(next @ ('$' | '`' | '\\')) => {
+                    chars.next();
+                    word.push(next, true);
                 }
-            }
-            None
-        };
+                _ => word.push('\\', true),
+            },
+            '$' => word.push_dollar(&mut chars, true),
+            '`' => word.push_backquote(&mut chars, true),
+            _ => word.push(ch, true),
+        }
+    }
+    if word.parts.is_empty() {
+        word.parts.push(WordPart::Text {
+            text: String::new(),
+            quoted: true,
+        });
+    }
+    word.into_token()
+}
 
-        if !token.quoted {
-            if let Some((is_stdout, mode, tail)) = parse_op(&token.text) {
-                let target = if tail.is_empty() {
-                    if i + 1 >= tokens.len() {
-                        args.push(token.text.clone());
-                        i += 1;
-                        continue;
-                    }
-                    i += 2;
-                    tokens[i - 1].text.clone()
-                } else {
-                    i += 1;
-                    tail
-                };
+/// Reads the bodies of any `<<` and `<<-` redirections in `tokens` from
+/// `next_line`, replacing each delimiter word with the collected body. An
+/// error from `next_line`, such as an interrupt, abandons the rest.
+fn read_heredocs(
+    tokens: &mut [ParsedToken],
+    next_line: &mut dyn FnMut() -> io::Result<Option<String>>,
+) -> io::Result<()> {
+    for idx in 1..tokens.len() {
+        let op = tokens[idx - 1]
+            .text
+            .trim_start_matches(|c: char| c.is_ascii_digit());
+        if !tokens[idx - 1].operator || !matches!(op, "<<" | "<<-") || tokens[idx].operator {
+            continue;
+        }
+        let strip_tabs = op == "<<-";
+        let delimiter = tokens[idx].text.clone();
+        let expand = !tokens[idx]
+            .parts
+            .iter()
+            .any(|part| matches!(part, WordPart::Text { quoted: true, .. }));
 
-                if is_stdout {
-                    redirects.stdout = Some((PathBuf::from(target), mode));
-                } else {
-                    redirects.stderr = Some((PathBuf::from(target), mode));
-                }
-                continue;
+        let mut body = String::new();
+        loop {
+            let Some(line) = next_line()? else {
+                write_output(
+                    &format!(
+                        "shell: warning: here-document delimited by end-of-file (wanted `{delimiter}')\n"
+                    ),
+                    OutputStream::Stderr,
+                    &FdTable::default(),
+                );
+                break;
+            };
+            let line = if strip_tabs {
+                line.trim_start_matches('\t')
+            } else {
+                line.as_str()
+            };
+            if line == delimiter {
+                break;
             }
+            body.push_str(line);
+            body.push('\n');
         }
+        tokens[idx] = parse_heredoc_body(&body, expand);
+    }
+    Ok(())
+}
 
-        args.push(token.text.clone());
-        i += 1;
+fn escape_glob(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if matches!(ch, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
     }
+    escaped
+}
 
-    (args, redirects)
+fn bracket_class_matches(class: &str, ch: char) -> bool {
+    match class {
+        "alpha" => ch.is_alphabetic(),
+        "digit" => ch.is_ascii_digit(),
+        "alnum" => ch.is_alphanumeric(),
+        "upper" => ch.is_uppercase(),
+        "lower" => ch.is_lowercase(),
+        "space" => ch.is_whitespace(),
+        "blank" => ch == ' ' || ch == '\t',
+        "punct" => ch.is_ascii_punctuation(),
+        "xdigit" => ch.is_ascii_hexdigit(),
+        _ => false,
+    }
 }
 
-fn split_pipeline(tokens: Vec<ParsedToken>) -> Vec<Vec<ParsedToken>> {
-    let mut stages = Vec::new();
-    let mut current = Vec::new();
+/// Matches `ch` against the bracket expression starting at `pattern[start]`.
+/// Returns whether it matched and the index just past the closing `]`, or
+/// `None` when the `[` does not open a valid bracket expression.
+fn match_bracket(pattern: &[char], start: usize, ch: char) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negate = matches!(pattern.get(i), Some('!' | '^'));
+    if negate {
+        i += 1;
+    }
 
-    for token in tokens {
-        if !token.quoted && token.text == "|" {
-            stages.push(current);
-            current = Vec::new();
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        let mut c = pattern[i];
+        if c == ']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+
+        if c == '[' && pattern.get(i + 1) == Some(&':') {
+            let rest: String = pattern[i + 2..].iter().collect();
+            if let Some(end) = rest.find(":]") {
+                matched |= bracket_class_matches(&rest[..end], ch);
+                i += 2 + rest[..end].chars().count() + 2;
+                continue;
+            }
+        }
+
+        if c == '\\' && i + 1 < pattern.len() {
+            i += 1;
+            c = pattern[i];
+        }
+
+        if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|&end| end != ']') {
+            let mut end = pattern[i + 2];
+            i += 2;
+            if end == '\\' && i + 1 < pattern.len() {
+                i += 1;
+                end = pattern[i];
+            }
+            matched |= c <= ch && ch <= end;
         } else {
-            current.push(token);
+            matched |= c == ch;
         }
+        i += 1;
     }
 
-    stages.push(current);
-    stages
-}
-
-fn is_builtin_command(cmd: &str) -> bool {
-    matches!(cmd, "echo" | "exit" | "type" | "pwd" | "cd" | "history")
+    None
 }
 
-#[derive(Default)]
-struct CommandResult {
-    stdout: Vec<u8>,
-    stderr: Vec<u8>,
-    should_exit: bool,
-}
+fn glob_match_chars(pattern: &[char], text: &[char]) -> bool {
+    let mut p = 0;
+    let mut t = 0;
+    let mut backtrack: Option<(usize, usize)> = None;
 
-fn format_history(history: &[String], limit: Option<usize>) -> Vec<u8> {
-    let start = match limit {
-        Some(n) => history.len().saturating_sub(n),
-        None => 0,
-    };
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                '*' => {
+                    backtrack = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                '?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                '[' => match match_bracket(pattern, p, text[t]) {
+                    Some((true, next)) => {
+                        p = next;
+                        t += 1;
+                        continue;
+                    }
+                    Some((false, _)) => {}
+                    None if text[t] == '[' => {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                    None => {}
+                },
+                '\\' if p + 1 < pattern.len() && pattern[p + 1] == text[t] => {
+                    p += 2;
+                    t += 1;
+                    continue;
+                }
+                '\\' if p + 1 < pattern.len() => {}
+                c if c == text[t] => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
 
-    let mut out = String::new();
-    for (idx, entry) in history.iter().enumerate().skip(start) {
-        out.push_str(&format!("{:>5}  {entry}\n", idx + 1));
+        let Some((star, matched)) = backtrack else {
+            return false;
+        };
+        p = star + 1;
+        t = matched + 1;
+        backtrack = Some((star, matched + 1));
     }
-    out.into_bytes()
-}
 
-fn read_history_file(path: &Path) -> io::Result<Vec<String>> {
-    let content = fs::read_to_string(path)?;
-    Ok(content.lines().map(|line| line.to_string()).collect())
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
-fn write_history_file(path: &Path, history: &[String]) -> io::Result<()> {
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(path)?;
+fn strip_pattern(value: &str, pattern: &str, prefix: bool, longest: bool) -> String {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let chars: Vec<char> = value.chars().collect();
+    let len = chars.len();
+    let cuts: Box<dyn Iterator<Item = usize>> = if prefix == longest {
+        Box::new((0..=len).rev())
+    } else {
+        Box::new(0..=len)
+    };
 
-    for entry in history {
-        writeln!(file, "{entry}")?;
+    for cut in cuts {
+        let (candidate, rest) = if prefix {
+            (&chars[..cut], &chars[cut..])
+        } else {
+            (&chars[cut..], &chars[..cut])
+        };
+        if glob_match_chars(&pattern, candidate) {
+            return rest.iter().collect();
+        }
     }
-
-    Ok(())
+    value.to_string()
 }
 
-fn append_history_file(path: &Path, history: &[String], from: usize) -> io::Result<()> {
-    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
-    for entry in history.iter().skip(from) {
-        writeln!(file, "{entry}")?;
+/// Replaces the longest match of `pattern` in `value`, optionally anchored to
+/// the start or end of the value, once or (with `all`) for every occurrence.
+fn replace_pattern(
+    value: &str,
+    pattern: &str,
+    replacement: &str,
+    anchor: Option<char>,
+    all: bool,
+) -> String {
+    if pattern.is_empty() {
+        return value.to_string();
     }
-    Ok(())
-}
-
-fn env_truthy(name: &str) -> bool {
-    env::var(name)
-        .ok()
-        .map(|value| {
-            matches!(
-                value.to_ascii_lowercase().as_str(),
-                "1" | "true" | "yes" | "on"
-            )
-        })
-        .unwrap_or(false)
-}
 
-fn should_append_history_on_exit() -> bool {
-    env_truthy("HISTAPPEND") || env_truthy("HISTFILE_APPEND") || env_truthy("HISTORY_APPEND")
-}
+    let pattern: Vec<char> = pattern.chars().collect();
+    let chars: Vec<char> = value.chars().collect();
+    let mut out = String::new();
+    let mut start = 0;
 
-fn run_history_command(
-    args: &[String],
-    history: &mut Vec<String>,
-    histfile: Option<&PathBuf>,
-    history_last_persisted: &mut usize,
-) -> CommandResult {
-    let mut result = CommandResult::default();
+    while start <= chars.len() {
+        let matched_end = (start..=chars.len())
+            .rev()
+            .filter(|&end| anchor != Some('%') || end == chars.len())
+            .find(|&end| glob_match_chars(&pattern, &chars[start..end]));
 
-    match args.first().map(String::as_str) {
-        Some("-r") => {
-            let path = args.get(1).map(PathBuf::from).or_else(|| histfile.cloned());
-            if let Some(path) = path {
-                if let Ok(lines) = read_history_file(&path) {
-                    history.extend(lines);
-                    *history_last_persisted = history.len();
+        match matched_end {
+            Some(end) => {
+                out.push_str(replacement);
+                if end == start {
+                    if let Some(&ch) = chars.get(start) {
+                        out.push(ch);
+                    }
+                    start += 1;
+                } else {
+                    start = end;
                 }
-            }
-        }
-        Some("-w") => {
-            let path = args.get(1).map(PathBuf::from).or_else(|| histfile.cloned());
-            if let Some(path) = path {
-                if write_history_file(&path, history).is_ok() {
-                    *history_last_persisted = history.len();
+                if !all {
+                    out.extend(&chars[start.min(chars.len())..]);
+                    return out;
                 }
             }
-        }
-        Some("-a") => {
-            let path = args.get(1).map(PathBuf::from).or_else(|| histfile.cloned());
-            if let Some(path) = path {
-                if append_history_file(&path, history, *history_last_persisted).is_ok() {
-                    *history_last_persisted = history.len();
+            None => {
+                if anchor == Some('#') {
+                    return value.to_string();
                 }
+                if let Some(&ch) = chars.get(start) {
+                    out.push(ch);
+                }
+                start += 1;
             }
         }
-        Some(value) => {
-            let limit = value.parse::<usize>().ok();
-            result.stdout = format_history(history, limit);
-        }
-        None => {
-            result.stdout = format_history(history, None);
+
+        if anchor == Some('#') {
+            out.extend(&chars[start.min(chars.len())..]);
+            return out;
         }
     }
 
-    result
+    out
 }
 
-fn run_builtin(
-    cmd: &str,
-    args: &[String],
-    allow_exit: bool,
-    apply_cd: bool,
-    history: &[String],
-) -> Option<CommandResult> {
-    let mut result = CommandResult::default();
+/// Splits the body of a `${...}` expansion into its length flag, parameter
+/// name and the operator text that follows the name.
+fn split_parameter_expr(expr: &str) -> Option<(bool, &str, &str)> {
+    let (length, body) = match expr.strip_prefix('#') {
+        Some(rest) if !rest.is_empty() => (true, rest),
+        _ => (false, expr),
+    };
 
-    match cmd {
-        "exit" => {
-            if allow_exit {
-                result.should_exit = true;
-            }
-        }
-        "echo" => {
-            result.stdout = format!("{}\n", args.join(" ")).into_bytes();
-        }
-        "pwd" => {
-            if let Ok(dir) = env::current_dir() {
-                result.stdout = format!("{}\n", dir.display()).into_bytes();
-            }
-        }
-        "cd" => {
-            if apply_cd {
-                if let Some(target) = args.first() {
-                    let resolved = if target == "~" {
-                        env::var_os("HOME").map(PathBuf::from)
-                    } else {
-                        Some(PathBuf::from(target))
-                    };
+    let end = if body.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        body.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(body.len())
+    } else if body.starts_with(|c: char| c.is_ascii_digit()) {
+        body.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(body.len())
+    } else if body.starts_with(['?', '$', '!', '#', '@', '*']) {
+        1
+    } else {
+        return None;
+    };
 
-                    match resolved {
-                        Some(path) => {
-                            if env::set_current_dir(&path).is_err() {
-                                result.stderr =
-                                    format!("cd: {target}: No such file or directory\n").into_bytes();
-                            }
-                        }
-                        None => {
-                            result.stderr =
-                                format!("cd: {target}: No such file or directory\n").into_bytes();
-                        }
-                    }
-                }
-            }
-        }
-        "type" => {
-            if let Some(query) = args.first() {
-                if is_builtin_command(query) {
-                    result.stdout = format!("{query} is a shell builtin\n").into_bytes();
-                } else if let Some(path) = find_in_path(query) {
-                    result.stdout = format!("{query} is {}\n", path.display()).into_bytes();
-                } else {
-                    result.stdout = format!("{query}: not found\n").into_bytes();
-                }
-            }
-        }
-        "history" => {
-            let limit = args.first().and_then(|s| s.parse::<usize>().ok());
-            result.stdout = format_history(history, limit);
-        }
-        _ => return None,
+    let (name, rest) = body.split_at(end);
+    if length && !rest.is_empty() {
+        return None;
     }
-
-    Some(result)
+    Some((length, name, rest))
 }
 
-fn open_redirect_file(path: &Path, mode: RedirectMode) -> io::Result<fs::File> {
-    let mut options = OpenOptions::new();
-    options.write(true).create(true);
-    match mode {
-        RedirectMode::Truncate => {
-            options.truncate(true);
+/// Splits a `${NAME/pattern/replacement}` operand at the first unquoted `/`.
+fn split_substitution(operand: &str) -> (&str, &str) {
+    let mut in_single = false;
+    let mut in_double = false;
+    let mut escaped = false;
+    for (idx, ch) in operand.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
         }
-        RedirectMode::Append => {
-            options.append(true);
+        match ch {
+            '\\' if !in_single => escaped = true,
+            '\'' if !in_double => in_single = !in_single,
+            '"' if !in_single => in_double = !in_double,
+            '/' if !in_single && !in_double => return (&operand[..idx], &operand[idx + 1..]),
+            _ => {}
         }
     }
-    options.open(path)
-}
-
-fn ensure_redirect_files(redirects: &RedirectSpec) {
-    if let Some((path, mode)) = &redirects.stdout {
-        let _ = open_redirect_file(path, *mode);
-    }
-    if let Some((path, mode)) = &redirects.stderr {
-        let _ = open_redirect_file(path, *mode);
-    }
+    (operand, "")
 }
 
-enum OutputStream {
-    Stdout,
-    Stderr,
+#[derive(Clone, Default)]
+struct ShellOptions {
+    nullglob: bool,
+    failglob: bool,
+    dotglob: bool,
+    globstar: bool,
+    noclobber: bool,
 }
 
-fn write_bytes_output(bytes: &[u8], stream: OutputStream, redirects: &RedirectSpec) {
-    let redirection = match stream {
-        OutputStream::Stdout => &redirects.stdout,
-        OutputStream::Stderr => &redirects.stderr,
-    };
+const SHOPT_NAMES: [&str; 4] = ["dotglob", "failglob", "globstar", "nullglob"];
+const SET_OPTION_NAMES: [&str; 1] = ["noclobber"];
 
-    if let Some((path, mode)) = redirection {
-        if let Ok(mut file) = open_redirect_file(path, *mode) {
-            let _ = file.write_all(bytes);
+impl ShellOptions {
+    fn shopt_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "dotglob" => Some(&mut self.dotglob),
+            "failglob" => Some(&mut self.failglob),
+            "globstar" => Some(&mut self.globstar),
+            "nullglob" => Some(&mut self.nullglob),
+            _ => None,
         }
-        return;
     }
 
-    match stream {
-        OutputStream::Stdout => {
-            let _ = io::stdout().write_all(bytes);
-            let _ = io::stdout().flush();
-        }
-        OutputStream::Stderr => {
-            let _ = io::stderr().write_all(bytes);
-            let _ = io::stderr().flush();
+    fn set_option_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "noclobber" => Some(&mut self.noclobber),
+            _ => None,
         }
     }
 }
 
-fn write_output(text: &str, stream: OutputStream, redirects: &RedirectSpec) {
-    write_bytes_output(text.as_bytes(), stream, redirects);
+fn has_glob_meta(component: &str) -> bool {
+    let mut chars = component.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
 }
 
-fn run_external_capture(stage: &PipelineStage, input: &[u8]) -> io::Result<CommandResult> {
-    let mut command = Command::new(&stage.cmd);
-    command.args(&stage.args);
-    command.stdout(Stdio::piped());
-    command.stderr(Stdio::piped());
-    command.stdin(Stdio::piped());
-
-    let mut child = command.spawn()?;
-
-    if let Some(mut stdin) = child.stdin.take() {
-        let _ = stdin.write_all(input);
+fn unescape_glob(component: &str) -> String {
+    let mut text = String::with_capacity(component.len());
+    let mut chars = component.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            text.extend(chars.next());
+        } else {
+            text.push(ch);
+        }
     }
-
-    let output = child.wait_with_output()?;
-    Ok(CommandResult {
-        stdout: output.stdout,
-        stderr: output.stderr,
-        should_exit: false,
-    })
+    text
 }
 
-fn build_pipeline_stages(segments: Vec<Vec<ParsedToken>>) -> Vec<PipelineStage> {
-    let mut stages = Vec::new();
-    for segment in segments {
-        let (tokens, redirects) = parse_redirections(segment);
-        let Some(cmd) = tokens.first().cloned() else {
-            continue;
-        };
-        stages.push(PipelineStage {
-            cmd,
-            args: tokens[1..].to_vec(),
-            redirects,
-        });
+fn join_glob_path(base: &str, name: &str) -> String {
+    if base.is_empty() {
+        name.to_string()
+    } else if base.ends_with('/') {
+        format!("{base}{name}")
+    } else {
+        format!("{base}/{name}")
     }
-    stages
 }
 
-fn execute_external_pipeline(stages: &[PipelineStage]) {
-    if stages.is_empty() {
-        return;
-    }
+fn glob_dir(base: &str) -> &Path {
+    Path::new(if base.is_empty() { "." } else { base })
+}
 
-    for stage in stages {
-        if find_in_path(&stage.cmd).is_none() {
-            write_output(
-                &format!("{}: command not found\n", stage.cmd),
-                OutputStream::Stdout,
-                &stage.redirects,
-            );
-            return;
+fn sorted_dir_entries(base: &str) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(glob_dir(base)) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Collects everything below `base` for a `**` component, descending into
+/// directories but not through symlinks. With `files` unset only
+/// directories are recorded.
+fn collect_globstar(base: &str, files: bool, dotglob: bool, out: &mut Vec<String>) {
+    for name in sorted_dir_entries(base) {
+        if name.starts_with('.') && !dotglob {
+            continue;
+        }
+        let path = join_glob_path(base, &name);
+        let is_dir = fs::symlink_metadata(&path).is_ok_and(|meta| meta.is_dir());
+        if is_dir || files {
+            out.push(path.clone());
+        }
+        if is_dir {
+            collect_globstar(&path, files, dotglob, out);
         }
-        ensure_redirect_files(&stage.redirects);
     }
+}
 
-    let mut children = Vec::new();
-    let mut previous_stdout = None;
-    let last_index = stages.len() - 1;
-
-    for (idx, stage) in stages.iter().enumerate() {
-        let mut command = Command::new(&stage.cmd);
-        command.args(&stage.args);
+fn expand_glob(pattern: &str, options: &ShellOptions) -> Vec<String> {
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    let root = if pattern.starts_with('/') { "/" } else { "" };
+    let mut paths = vec![root.to_string()];
 
-        if let Some(stdout) = previous_stdout.take() {
-            command.stdin(Stdio::from(stdout));
-        }
+    for (idx, component) in components.iter().enumerate() {
+        let last = idx + 1 == components.len();
+        let mut next = Vec::new();
 
-        if idx < last_index {
-            command.stdout(Stdio::piped());
-        } else if let Some((path, mode)) = &stage.redirects.stdout {
-            if let Ok(file) = open_redirect_file(path, *mode) {
-                command.stdout(Stdio::from(file));
+        for base in &paths {
+            if options.globstar && *component == "**" {
+                if !last {
+                    next.push(base.clone());
+                }
+                collect_globstar(base, last, options.dotglob, &mut next);
+            } else if has_glob_meta(component) {
+                let pattern: Vec<char> = component.chars().collect();
+                let allow_hidden = component.starts_with('.') || options.dotglob;
+                for name in sorted_dir_entries(base) {
+                    if name.starts_with('.') && !allow_hidden {
+                        continue;
+                    }
+                    let chars: Vec<char> = name.chars().collect();
+                    if glob_match_chars(&pattern, &chars) {
+                        next.push(join_glob_path(base, &name));
+                    }
+                }
+            } else {
+                next.push(join_glob_path(base, &unescape_glob(component)));
             }
         }
 
-        if let Some((path, mode)) = &stage.redirects.stderr {
-            if let Ok(file) = open_redirect_file(path, *mode) {
-                command.stderr(Stdio::from(file));
-            }
+        if !last {
+            next.retain(|path| glob_dir(path).is_dir());
         }
+        paths = next;
+    }
 
-        let mut child = match command.spawn() {
-            Ok(child) => child,
-            Err(_) => {
-                write_output(
-                    &format!("{}: command not found\n", stage.cmd),
-                    OutputStream::Stdout,
-                    &stage.redirects,
-                );
-                return;
+    paths.retain(|path| !path.is_empty() && fs::symlink_metadata(path).is_ok());
+    if pattern.ends_with('/') {
+        paths.retain(|path| Path::new(path).is_dir());
+        for path in &mut paths {
+            if !path.ends_with('/') {
+                path.push('/');
             }
-        };
-
-        if idx < last_index {
-            previous_stdout = child.stdout.take();
         }
-        children.push(child);
-    }
-
-    for child in &mut children {
-        let _ = child.wait();
     }
+    paths.sort();
+    paths.dedup();
+    paths
 }
 
-fn execute_mixed_pipeline(stages: &[PipelineStage], history: &[String]) {
-    let mut stdin_buffer = Vec::new();
+#[derive(Clone)]
+enum BraceAtom {
+    Char(char, bool),
+    Part(WordPart),
+}
 
-    for (idx, stage) in stages.iter().enumerate() {
-        ensure_redirect_files(&stage.redirects);
-        let is_last = idx + 1 == stages.len();
+impl BraceAtom {
+    fn is_unquoted(&self, ch: char) -> bool {
+        matches!(self, BraceAtom::Char(c, false) if *c == ch)
+    }
+}
 
-        let result = if let Some(result) =
-            run_builtin(&stage.cmd, &stage.args, false, false, history)
-        {
-            result
-        } else {
-            if find_in_path(&stage.cmd).is_none() {
-                write_output(
-                    &format!("{}: command not found\n", stage.cmd),
-                    OutputStream::Stdout,
-                    &stage.redirects,
-                );
-                return;
+fn brace_atoms(token: &ParsedToken) -> Vec<BraceAtom> {
+    let mut atoms = Vec::new();
+    for part in &token.parts {
+        match part {
+            WordPart::Text { text, quoted } => {
+                atoms.extend(text.chars().map(|ch| BraceAtom::Char(ch, *quoted)));
             }
+            other => atoms.push(BraceAtom::Part(other.clone())),
+        }
+    }
+    atoms
+}
 
-            match run_external_capture(stage, &stdin_buffer) {
-                Ok(result) => result,
-                Err(_) => {
-                    write_output(
-                        &format!("{}: command not found\n", stage.cmd),
-                        OutputStream::Stdout,
-                        &stage.redirects,
-                    );
-                    return;
+fn token_from_atoms(atoms: Vec<BraceAtom>) -> ParsedToken {
+    let mut word = WordBuilder::default();
+    for atom in atoms {
+        match atom {
+            BraceAtom::Char(ch, quoted) => word.push(ch, quoted),
+            BraceAtom::Part(WordPart::Param { expr, quoted }) => {
+                let raw = format!("${{{expr}}}");
+                word.push_param(expr, &raw, quoted);
+            }
+            BraceAtom::Part(WordPart::Command { source, quoted }) => {
+                let raw = format!("$({source})");
+                word.push_command(source, &raw, quoted);
+            }
+            BraceAtom::Part(WordPart::Text { text, quoted }) => {
+                for ch in text.chars() {
+                    word.push(ch, quoted);
                 }
             }
-        };
-
-        if !result.stderr.is_empty() {
-            write_bytes_output(&result.stderr, OutputStream::Stderr, &stage.redirects);
-        }
-
-        if is_last {
-            write_bytes_output(&result.stdout, OutputStream::Stdout, &stage.redirects);
-        } else {
-            stdin_buffer = result.stdout;
         }
     }
+    word.into_token()
 }
 
-fn execute_pipeline(segments: Vec<Vec<ParsedToken>>, history: &[String]) {
-    let stages = build_pipeline_stages(segments);
-    if stages.is_empty() {
-        return;
+fn matching_brace(atoms: &[BraceAtom], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (idx, atom) in atoms.iter().enumerate().skip(open) {
+        if atom.is_unquoted('{') {
+            depth += 1;
+        } else if atom.is_unquoted('}') {
+            depth -= 1;
+            if depth == 0 {
+                return Some(idx);
+            }
+        }
     }
+    None
+}
 
-    if stages.iter().all(|stage| !is_builtin_command(&stage.cmd)) {
-        execute_external_pipeline(&stages);
-    } else {
-        execute_mixed_pipeline(&stages, history);
+fn split_brace_alternatives(inner: &[BraceAtom]) -> Vec<Vec<BraceAtom>> {
+    let mut alternatives = vec![Vec::new()];
+    let mut depth = 0;
+    for atom in inner {
+        if atom.is_unquoted('{') {
+            depth += 1;
+        } else if atom.is_unquoted('}') {
+            depth -= 1;
+        } else if depth == 0 && atom.is_unquoted(',') {
+            alternatives.push(Vec::new());
+            continue;
+        }
+        if let Some(current) = alternatives.last_mut() {
+            current.push(atom.clone());
+        }
     }
+    alternatives
 }
 
-fn main() {
-    #[cfg(unix)]
-    let _raw_mode = RawModeGuard::new(STDIN_FILENO).ok();
-    let histfile = env::var_os("HISTFILE").map(PathBuf::from);
-    let mut history = if let Some(path) = histfile.as_ref() {
-        read_history_file(path).unwrap_or_default()
-    } else {
-        Vec::new()
-    };
-    let mut history_last_persisted = history.len();
+/// The most words a single brace sequence may produce; longer sequences are
+/// left unexpanded rather than exhausting memory.
+const MAX_BRACE_SEQUENCE: u64 = 1 << 20;
 
-    loop {
-        print!("{PROMPT}");
-        io::stdout().flush().unwrap();
+/// Expands the body of a `{start..end[..step]}` sequence over integers or
+/// single characters, zero-padding integers when either bound is padded.
+fn brace_sequence(inner: &[BraceAtom]) -> Option<Vec<Vec<BraceAtom>>> {
+    let mut text = String::new();
+    for atom in inner {
+        match atom {
+            BraceAtom::Char(ch, false) => text.push(*ch),
+            _ => return None,
+        }
+    }
 
-        let Some(input) = read_user_input(&history).unwrap() else {
-            break; // EOF
-        };
+    let bounds: Vec<&str> = text.split("..").collect();
+    if !(2..=3).contains(&bounds.len()) {
+        return None;
+    }
+    let step = match bounds.get(2) {
+        Some(step) => step.parse::<i64>().ok()?.unsigned_abs().max(1),
+        None => 1,
+    };
 
-        let tokens = parse_line(&input);
-        if tokens.is_empty() {
+    let values: Vec<String> =
+        if let (Ok(start), Ok(end)) = (bounds[0].parse::<i64>(), bounds[1].parse::<i64>()) {
+            let padded = |bound: &str| {
+                let digits = bound.trim_start_matches('-');
+                digits.len() > 1 && digits.starts_with('0')
+            };
+            let width = if padded(bounds[0]) || padded(bounds[1]) {
+                bounds[0].len().max(bounds[1].len())
+            } else {
+                0
+            };
+            let count = sequence_length(start.abs_diff(end), step)?;
+            let direction = if start <= end { 1 } else { -1 };
+            (0..count)
+                .map(|idx| {
+                    // Every value lies between the bounds, so it fits an i64
+                    // even when the distance from `start` does not.
+                    let value = (i128::from(start) + direction * i128::from(idx * step)) as i64;
+                    if value < 0 {
+                        format!(
+                            "-{:0>width$}",
+                            value.unsigned_abs(),
+                            width = width.saturating_sub(1)
+                        )
+                    } else {
+                        format!("{value:0>width$}")
+                    }
+                })
+                .collect()
+        } else {
+            let mut start_chars = bounds[0].chars();
+            let mut end_chars = bounds[1].chars();
+            let (Some(start), None, Some(end), None) = (
+                start_chars.next(),
+                start_chars.next(),
+                end_chars.next(),
+                end_chars.next(),
+            ) else {
+                return None;
+            };
+            let (start, end) = (start as u32, end as u32);
+            let count = sequence_length(u64::from(start.abs_diff(end)), step)?;
+            (0..count)
+                .filter_map(|idx| {
+                    let offset = (idx * step) as u32;
+                    let code = if start <= end {
+                        start + offset
+                    } else {
+                        start - offset
+                    };
+                    char::from_u32(code).map(String::from)
+                })
+                .collect()
+        };
+
+    Some(
+        values
+            .into_iter()
+            .map(|value| value.chars().map(|ch| BraceAtom::Char(ch, true)).collect())
+            .collect(),
+    )
+}
+
+/// Counts the values from one bound to another `distance` away, or `None`
+/// when there are more than [`MAX_BRACE_SEQUENCE`].
+fn sequence_length(distance: u64, step: u64) -> Option<u64> {
+    (distance / step)
+        .checked_add(1)
+        .filter(|&count| count <= MAX_BRACE_SEQUENCE)
+}
+
+fn expand_brace_atoms(atoms: &[BraceAtom]) -> Vec<Vec<BraceAtom>> {
+    let mut start = 0;
+    while let Some(offset) = atoms[start..].iter().position(|atom| atom.is_unquoted('{')) {
+        let open = start + offset;
+        if let Some(close) = matching_brace(atoms, open) {
+            let inner = &atoms[open + 1..close];
+            let alternatives = split_brace_alternatives(inner);
+            let items = if alternatives.len() > 1 {
+                Some(alternatives)
+            } else {
+                brace_sequence(inner)
+            };
+
+            if let Some(items) = items {
+                let prefix = &atoms[..open];
+                let suffixes = expand_brace_atoms(&atoms[close + 1..]);
+                let mut words = Vec::new();
+                for item in items {
+                    for middle in expand_brace_atoms(&item) {
+                        for suffix in &suffixes {
+                            let mut word = prefix.to_vec();
+                            word.extend(middle.iter().cloned());
+                            word.extend(suffix.iter().cloned());
+                            words.push(word);
+                        }
+                    }
+                }
+                return words;
+            }
+        }
+        start = open + 1;
+    }
+    vec![atoms.to_vec()]
+}
+
+/// Performs brace expansion on a word. Only unquoted braces and commas are
+/// structural, so `"{a,b}"` and `\\{a,b}` are left alone.
+fn expand_braces(token: ParsedToken) -> Vec<ParsedToken> {
+    if token.operator || !token.text.contains('{') {
+        return vec![token];
+    }
+    expand_brace_atoms(&brace_atoms(&token))
+        .into_iter()
+        .map(token_from_atoms)
+        .collect()
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[derive(Copy, Clone)]
+enum RedirectMode {
+    Truncate,
+    Clobber,
+    Append,
+    Read,
+    ReadWrite,
+}
+
+#[derive(Clone)]
+enum RedirectTarget {
+    File(PathBuf, RedirectMode),
+    Text(String),
+    Duplicate(i32),
+    Close,
+}
+
+#[derive(Clone)]
+struct Redirect {
+    fd: i32,
+    target: RedirectTarget,
+}
+
+/// The redirections of a command, in the order they appear on the line.
+#[derive(Default, Clone)]
+struct RedirectSpec {
+    redirects: Vec<Redirect>,
+}
+
+impl RedirectSpec {
+    fn push(&mut self, fd: i32, target: RedirectTarget) {
+        self.redirects.push(Redirect { fd, target });
+    }
+}
+
+#[derive(Clone)]
+struct PipelineStage {
+    cmd: String,
+    args: Vec<String>,
+    redirects: RedirectSpec,
+    assignments: Vec<(String, String)>,
+    env: Vec<(String, String)>,
+}
+
+impl PipelineStage {
+    fn search_path(&self) -> Option<&str> {
+        self.env
+            .iter()
+            .find(|(name, _)| name == "PATH")
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Builds the process for this stage, running `program` under the name
+    /// the command was given as.
+    fn command(&self, program: &Path) -> Command {
+        let mut command = Command::new(program);
+        #[cfg(unix)]
+        command.arg0(&self.cmd);
+        command.args(&self.args);
+        command.env_clear();
+        command.envs(self.env.iter().map(|(name, value)| (name, value)));
+        command
+    }
+}
+
+/// Splits a leading `NAME=value` word into the variable name and the parts
+/// making up its value.
+fn split_assignment(token: &ParsedToken) -> Option<(String, Vec<WordPart>)> {
+    if token.operator {
+        return None;
+    }
+    let Some(WordPart::Text {
+        text,
+        quoted: false,
+    }) = token.parts.first()
+    else {
+        return None;
+    };
+    let (name, value) = text.split_once('=')?;
+    if !is_valid_name(name) {
+        return None;
+    }
+
+    let mut parts = Vec::with_capacity(token.parts.len());
+    if !value.is_empty() {
+        parts.push(WordPart::Text {
+            text: value.to_string(),
+            quoted: false,
+        });
+    }
+    parts.extend(token.parts[1..].iter().cloned());
+    Some((name.to_string(), parts))
+}
+
+/// Reports whether a simple command names something to run, as opposed to
+/// consisting only of assignments and redirections.
+fn has_command_word(segment: &[ParsedToken]) -> bool {
+    let mut words = segment
+        .iter()
+        .skip_while(|token| split_assignment(token).is_some())
+        .peekable();
+    while let Some(token) = words.next() {
+        if !token.operator {
+            return true;
+        }
+        if split_redirect_operator(&token.text).is_some() {
+            words.next_if(|next| !next.operator);
+        }
+    }
+    false
+}
+
+/// Splits a redirection operator such as `2>&` into its explicit file
+/// descriptor, if any, and the operator itself.
+fn split_redirect_operator(text: &str) -> Option<(Option<i32>, &str)> {
+    let start = text.find(|c: char| !c.is_ascii_digit())?;
+    let (digits, op) = text.split_at(start);
+    let fd = if digits.is_empty() {
+        None
+    } else {
+        Some(digits.parse().ok()?)
+    };
+    Some((fd, op))
+}
+
+fn parse_redirections(
+    tokens: Vec<ParsedToken>,
+) -> Result<(Vec<String>, RedirectSpec), RedirectError> {
+    let mut args = Vec::new();
+    let mut redirects = RedirectSpec::default();
+    let mut tokens = tokens.into_iter().peekable();
+
+    while let Some(token) = tokens.next() {
+        let split = if token.operator {
+            split_redirect_operator(&token.text)
+        } else {
+            None
+        };
+        let Some((fd, op)) = split else {
+            args.push(token.text);
+            continue;
+        };
+        let Some(target) = tokens.next_if(|next| !next.operator) else {
+            args.push(token.text);
+            continue;
+        };
+        let target = target.text;
+
+        match op {
+            ">" => redirects.push(
+                fd.unwrap_or(1),
+                RedirectTarget::File(PathBuf::from(target), RedirectMode::Truncate),
+            ),
+            ">|" => redirects.push(
+                fd.unwrap_or(1),
+                RedirectTarget::File(PathBuf::from(target), RedirectMode::Clobber),
+            ),
+            ">>" => redirects.push(
+                fd.unwrap_or(1),
+                RedirectTarget::File(PathBuf::from(target), RedirectMode::Append),
+            ),
+            "<" => redirects.push(
+                fd.unwrap_or(0),
+                RedirectTarget::File(PathBuf::from(target), RedirectMode::Read),
+            ),
+            "<>" => redirects.push(
+                fd.unwrap_or(0),
+                RedirectTarget::File(PathBuf::from(target), RedirectMode::ReadWrite),
+            ),
+            "<<" | "<<-" => redirects.push(fd.unwrap_or(0), RedirectTarget::Text(target)),
+            "<<<" => redirects.push(fd.unwrap_or(0), RedirectTarget::Text(format!("{target}\n"))),
+            ">&" | "<&" => {
+                let default_fd = if op == ">&" { 1 } else { 0 };
+                if target == "-" {
+                    redirects.push(fd.unwrap_or(default_fd), RedirectTarget::Close);
+                } else if let Ok(source) = target.parse::<i32>() {
+                    redirects.push(fd.unwrap_or(default_fd), RedirectTarget::Duplicate(source));
+                } else if op == ">&" && fd.is_none() {
+                    redirects.push(
+                        1,
+                        RedirectTarget::File(PathBuf::from(target), RedirectMode::Truncate),
+                    );
+                    redirects.push(2, RedirectTarget::Duplicate(1));
+                } else {
+                    return Err(RedirectError::Ambiguous(target));
+                }
+            }
+            "&>" | "&>>" => {
+                let mode = if op == "&>" {
+                    RedirectMode::Truncate
+                } else {
+                    RedirectMode::Append
+                };
+                redirects.push(1, RedirectTarget::File(PathBuf::from(target), mode));
+                redirects.push(2, RedirectTarget::Duplicate(1));
+            }
+            _ => {
+                args.push(token.text);
+                args.push(target);
+            }
+        }
+    }
+
+    Ok((args, redirects))
+}
+
+fn split_pipeline(tokens: Vec<ParsedToken>) -> Vec<Vec<ParsedToken>> {
+    let mut stages = Vec::new();
+    let mut current = Vec::new();
+
+    for token in tokens {
+        if token.is_operator("|") {
+            stages.push(std::mem::take(&mut current));
+        } else if token.is_operator("|&") {
+            current.push(ParsedToken::operator("2>&"));
+            current.push(ParsedToken::literal("1".to_string()));
+            stages.push(std::mem::take(&mut current));
+        } else {
+            current.push(token);
+        }
+    }
+
+    stages.push(current);
+    stages
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ListOperator {
+    Sequence,
+    And,
+    Or,
+}
+
+/// Pipelines joined by `&&` and `||`, run as a unit and, when terminated by
+/// `&`, in the background.
+struct AndOrList {
+    pipelines: Vec<(ListOperator, Vec<ParsedToken>)>,
+    background: bool,
+}
+
+fn split_command_list(tokens: Vec<ParsedToken>) -> Result<Vec<AndOrList>, String> {
+    let mut lists = Vec::new();
+    let mut pipelines = Vec::new();
+    let mut current = Vec::new();
+    let mut connector = ListOperator::Sequence;
+
+    for token in tokens {
+        let next = if token.is_operator(";") || token.is_operator("&") {
+            ListOperator::Sequence
+        } else if token.is_operator("&&") {
+            ListOperator::And
+        } else if token.is_operator("||") {
+            ListOperator::Or
+        } else {
+            current.push(token);
             continue;
+        };
+
+        if current.is_empty() {
+            return Err(token.text);
+        }
+        pipelines.push((connector, std::mem::take(&mut current)));
+        connector = next;
+        if next == ListOperator::Sequence {
+            lists.push(AndOrList {
+                pipelines: std::mem::take(&mut pipelines),
+                background: token.is_operator("&"),
+            });
+        }
+    }
+
+    if current.is_empty() {
+        if connector != ListOperator::Sequence {
+            return Err("newline".to_string());
+        }
+    } else {
+        pipelines.push((connector, current));
+        lists.push(AndOrList {
+            pipelines,
+            background: false,
+        });
+    }
+
+    Ok(lists)
+}
+
+/// Reconstructs a readable command line for job listings.
+fn command_text(pipelines: &[(ListOperator, Vec<ParsedToken>)]) -> String {
+    let mut text = String::new();
+    for (connector, tokens) in pipelines {
+        match connector {
+            ListOperator::Sequence => {}
+            ListOperator::And => text.push_str(" &&"),
+            ListOperator::Or => text.push_str(" ||"),
+        }
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        text.push_str(&pipeline_text(tokens));
+    }
+    text
+}
+
+fn pipeline_text(tokens: &[ParsedToken]) -> String {
+    let mut text = String::new();
+    let mut glue = false;
+    for token in tokens {
+        if !text.is_empty() && !glue {
+            text.push(' ');
+        }
+        text.push_str(&token.text);
+        glue = token.operator && !token.is_operator("|") && !token.is_operator("|&");
+    }
+    text
+}
+
+fn is_builtin_command(cmd: &str) -> bool {
+    matches!(
+        cmd,
+        "echo"
+            | "exit"
+            | "type"
+            | "pwd"
+            | "cd"
+            | "history"
+            | "export"
+            | "unset"
+            | "readonly"
+            | "shopt"
+            | "set"
+            | "jobs"
+            | "fg"
+            | "bg"
+            | "wait"
+            | "disown"
+            | "trap"
+            | "kill"
+            | "hash"
+            | "source"
+            | "."
+    )
+}
+
+#[derive(Clone)]
+struct Variable {
+    value: String,
+    exported: bool,
+    readonly: bool,
+}
+
+/// Quotes `value` in single quotes for output that can be read back in.
+fn single_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn quote_value(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for ch in value.chars() {
+        if matches!(ch, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(ch);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[derive(Default)]
+struct CommandResult {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    status: i32,
+    should_exit: bool,
+}
+
+#[cfg(unix)]
+fn passwd_home_dir(entry: *const libc::passwd) -> Option<String> {
+    // SAFETY: `entry` comes straight from getpwnam/getpwuid and is only read
+    // before any other passwd lookup can overwrite the static buffer.
+    unsafe {
+        if entry.is_null() || (*entry).pw_dir.is_null() {
+            return None;
+        }
+        Some(
+            CStr::from_ptr((*entry).pw_dir)
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
+
+#[cfg(unix)]
+fn user_home_dir(user: &str) -> Option<String> {
+    if !user
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return None;
+    }
+    let name = CString::new(user).ok()?;
+    // SAFETY: `name` is a valid NUL-terminated string for the duration of the call.
+    passwd_home_dir(unsafe { libc::getpwnam(name.as_ptr()) })
+}
+
+#[cfg(unix)]
+fn current_user_home_dir() -> Option<String> {
+    // SAFETY: getuid cannot fail and getpwuid accepts any uid.
+    passwd_home_dir(unsafe { libc::getpwuid(libc::getuid()) })
+}
+
+#[cfg(not(unix))]
+fn user_home_dir(_user: &str) -> Option<String> {
+    None
+}
+
+#[cfg(not(unix))]
+fn current_user_home_dir() -> Option<String> {
+    None
+}
+
+/// Runs `run` with the shell's standard output redirected into a pipe and
+/// returns everything written to it, by builtins and child processes alike.
+#[cfg(unix)]
+fn capture_stdout(run: impl FnOnce()) -> io::Result<Vec<u8>> {
+    let stdout = io::stdout();
+    stdout.lock().flush()?;
+    let saved = stdout.as_fd().try_clone_to_owned()?;
+    let (mut reader, writer) = io::pipe()?;
+
+    // SAFETY: both descriptors are valid for the duration of the call.
+    if unsafe { libc::dup2(writer.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    drop(writer);
+
+    let collector = thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = reader.read_to_end(&mut output);
+        output
+    });
+
+    run();
+
+    let _ = stdout.lock().flush();
+    // SAFETY: `saved` is an owned duplicate of the original stdout.
+    unsafe {
+        libc::dup2(saved.as_raw_fd(), libc::STDOUT_FILENO);
+    }
+    collector
+        .join()
+        .map_err(|_| io::Error::other("command substitution reader panicked"))
+}
+
+#[cfg(not(unix))]
+fn capture_stdout(run: impl FnOnce()) -> io::Result<Vec<u8>> {
+    run();
+    Ok(Vec::new())
+}
+
+fn exit_status_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+
+    #[cfg(unix)]
+    if let Some(signal) = status.signal() {
+        return 128 + signal;
+    }
+
+    1
+}
+
+fn report_spawn_error(cmd: &str, err: &io::Error, fds: &FdTable) -> i32 {
+    if err.kind() == io::ErrorKind::PermissionDenied || cmd.contains('/') {
+        write_output(
+            &format!("{cmd}: {}\n", io_error_message(err)),
+            OutputStream::Stderr,
+            fds,
+        );
+        return STATUS_NOT_EXECUTABLE;
+    }
+
+    write_output(
+        &format!("{cmd}: command not found\n"),
+        OutputStream::Stdout,
+        fds,
+    );
+    STATUS_NOT_FOUND
+}
+
+/// Explains why a command given by path cannot be run, returning its exit
+/// status, or `None` if it looks runnable.
+fn report_path_error(cmd: &str, fds: &FdTable) -> Option<i32> {
+    let (message, status) = match fs::metadata(cmd) {
+        Err(_) => ("No such file or directory", STATUS_NOT_FOUND),
+        Ok(metadata) if metadata.is_dir() => ("Is a directory", STATUS_NOT_EXECUTABLE),
+        Ok(_) if !is_executable(Path::new(cmd)) => ("Permission denied", STATUS_NOT_EXECUTABLE),
+        Ok(_) => return None,
+    };
+    write_output(&format!("{cmd}: {message}\n"), OutputStream::Stderr, fds);
+    Some(status)
+}
+
+fn report_command_not_found(cmd: &str, fds: &FdTable) -> i32 {
+    write_output(
+        &format!("{cmd}: command not found\n"),
+        OutputStream::Stdout,
+        fds,
+    );
+    STATUS_NOT_FOUND
+}
+
+fn format_history(history: &[String], limit: Option<usize>) -> Vec<u8> {
+    let start = match limit {
+        Some(n) => history.len().saturating_sub(n),
+        None => 0,
+    };
+
+    let mut out = String::new();
+    for (idx, entry) in history.iter().enumerate().skip(start) {
+        out.push_str(&format!("{:>5}  {entry}\n", idx + 1));
+    }
+    out.into_bytes()
+}
+
+fn read_history_file(path: &Path) -> io::Result<Vec<String>> {
+    let content = fs::read_to_string(path)?;
+    Ok(content.lines().map(|line| line.to_string()).collect())
+}
+
+fn write_history_file(path: &Path, history: &[String]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+
+    for entry in history {
+        writeln!(file, "{entry}")?;
+    }
+
+    Ok(())
+}
+
+fn append_history_file(path: &Path, history: &[String], from: usize) -> io::Result<()> {
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    for entry in history.iter().skip(from) {
+        writeln!(file, "{entry}")?;
+    }
+    Ok(())
+}
+
+fn env_truthy(name: &str) -> bool {
+    env::var(name)
+        .ok()
+        .map(|value| {
+            matches!(
+                value.to_ascii_lowercase().as_str(),
+                "1" | "true" | "yes" | "on"
+            )
+        })
+        .unwrap_or(false)
+}
+
+fn should_append_history_on_exit() -> bool {
+    env_truthy("HISTAPPEND") || env_truthy("HISTFILE_APPEND") || env_truthy("HISTORY_APPEND")
+}
+
+fn run_history_command(
+    args: &[String],
+    history: &mut Vec<String>,
+    histfile: Option<&PathBuf>,
+    history_last_persisted: &mut usize,
+) -> CommandResult {
+    let mut result = CommandResult::default();
+
+    match args.first().map(String::as_str) {
+        Some("-r") => {
+            let path = args.get(1).map(PathBuf::from).or_else(|| histfile.cloned());
+            if let Some(path) = path
+                && let Ok(lines) = read_history_file(&path)
+            {
+                history.extend(lines);
+                *history_last_persisted = history.len();
+            }
+        }
+        Some("-w") => {
+            let path = args.get(1).map(PathBuf::from).or_else(|| histfile.cloned());
+            if let Some(path) = path
+                && write_history_file(&path, history).is_ok()
+            {
+                *history_last_persisted = history.len();
+            }
+        }
+        Some("-a") => {
+            let path = args.get(1).map(PathBuf::from).or_else(|| histfile.cloned());
+            if let Some(path) = path
+                && append_history_file(&path, history, *history_last_persisted).is_ok()
+            {
+                *history_last_persisted = history.len();
+            }
+        }
+        Some(value) => {
+            let limit = value.parse::<usize>().ok();
+            result.stdout = format_history(history, limit);
+        }
+        None => {
+            result.stdout = format_history(history, None);
+        }
+    }
+
+    result
+}
+
+/// Opens a redirection target. With `noclobber` set, a plain `>` refuses to
+/// replace an existing regular file and creates new files exclusively.
+fn open_redirect_file(path: &Path, mode: RedirectMode, noclobber: bool) -> io::Result<fs::File> {
+    let mut options = OpenOptions::new();
+    match mode {
+        RedirectMode::Truncate if noclobber => match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+            Ok(_) => {
+                options.write(true);
+            }
+            Err(_) => {
+                options.write(true).create_new(true);
+            }
+        },
+        RedirectMode::Truncate | RedirectMode::Clobber => {
+            options.write(true).create(true).truncate(true);
+        }
+        RedirectMode::Append => {
+            options.append(true).create(true);
+        }
+        RedirectMode::Read => {
+            options.read(true);
+        }
+        RedirectMode::ReadWrite => {
+            options.read(true).write(true).create(true);
+        }
+    }
+    options.open(path)
+}
+
+fn io_error_message(err: &io::Error) -> String {
+    let message = err.to_string();
+    match message.find(" (os error") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
+
+#[cfg(unix)]
+fn pipe_files() -> io::Result<(fs::File, fs::File)> {
+    let (reader, writer) = io::pipe()?;
+    Ok((OwnedFd::from(reader).into(), OwnedFd::from(writer).into()))
+}
+
+#[cfg(not(unix))]
+fn pipe_files() -> io::Result<(fs::File, fs::File)> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Returns the read end of a pipe yielding `bytes`, written from a separate
+/// thread so a large here-document cannot fill the pipe and block the shell.
+fn pipe_input(bytes: Vec<u8>) -> io::Result<fs::File> {
+    let (reader, mut writer) = pipe_files()?;
+    thread::spawn(move || {
+        let _ = writer.write_all(&bytes);
+    });
+    Ok(reader)
+}
+
+/// Duplicates `fd` onto a descriptor numbered 10 or above, out of the way of
+/// the low numbers redirections usually target.
+#[cfg(unix)]
+fn duplicate_fd(fd: i32) -> io::Result<fs::File> {
+    // SAFETY: F_DUPFD_CLOEXEC only reads `fd` and fails cleanly if it is not open.
+    let duplicate = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) };
+    if duplicate < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `duplicate` is a fresh descriptor that nothing else owns.
+    Ok(unsafe { fs::File::from_raw_fd(duplicate) })
+}
+
+#[cfg(not(unix))]
+fn duplicate_fd(_fd: i32) -> io::Result<fs::File> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Why a redirection or the output written through it failed.
+#[derive(Debug, thiserror::Error)]
+enum RedirectError {
+    #[error("{}: {}", .path.display(), io_error_message(.source))]
+    Open { path: PathBuf, source: io::Error },
+    #[error("{}: cannot overwrite existing file", .0.display())]
+    Clobber(PathBuf),
+    #[error("{0}: Bad file descriptor")]
+    BadDescriptor(i32),
+    #[error("{0}: ambiguous redirect")]
+    Ambiguous(String),
+    #[error("{}", io_error_message(.0))]
+    Pipe(#[from] io::Error),
+    #[error("{command}: write error: {}", io_error_message(.source))]
+    Write { command: String, source: io::Error },
+}
+
+/// The descriptors a command runs with, built by applying its redirections
+/// left to right. Descriptors without an entry are inherited from the shell,
+/// and `None` marks one closed with `>&-`.
+#[derive(Default)]
+struct FdTable {
+    fds: HashMap<i32, Option<fs::File>>,
+}
+
+impl FdTable {
+    fn piped(stdin: Option<fs::File>, stdout: Option<fs::File>) -> Self {
+        let mut fds = HashMap::new();
+        if let Some(stdin) = stdin {
+            fds.insert(0, Some(stdin));
+        }
+        if let Some(stdout) = stdout {
+            fds.insert(1, Some(stdout));
+        }
+        Self { fds }
+    }
+
+    fn set(&mut self, fd: i32, file: fs::File) -> io::Result<()> {
+        // Keep the file clear of the descriptors `configure` will dup2 onto.
+        #[cfg(unix)]
+        let file = if fd > 2 {
+            duplicate_fd(file.as_raw_fd())?
+        } else {
+            file
+        };
+        self.fds.insert(fd, Some(file));
+        Ok(())
+    }
+
+    fn duplicate(&self, fd: i32) -> io::Result<fs::File> {
+        match self.fds.get(&fd) {
+            Some(Some(file)) => file.try_clone(),
+            None if (0..=2).contains(&fd) => duplicate_fd(fd),
+            _ => Err(io::Error::other("Bad file descriptor")),
+        }
+    }
+
+    fn apply(&mut self, spec: &RedirectSpec, noclobber: bool) -> Result<(), RedirectError> {
+        for redirect in &spec.redirects {
+            let file = match &redirect.target {
+                RedirectTarget::File(path, mode) => open_redirect_file(path, *mode, noclobber)
+                    .map_err(|source| match source.kind() {
+                        io::ErrorKind::AlreadyExists if noclobber => {
+                            RedirectError::Clobber(path.clone())
+                        }
+                        _ => RedirectError::Open {
+                            path: path.clone(),
+                            source,
+                        },
+                    })?,
+                RedirectTarget::Text(text) => pipe_input(text.clone().into_bytes())?,
+                RedirectTarget::Duplicate(source) => self
+                    .duplicate(*source)
+                    .map_err(|_| RedirectError::BadDescriptor(*source))?,
+                RedirectTarget::Close => {
+                    self.fds.insert(redirect.fd, None);
+                    continue;
+                }
+            };
+            self.set(redirect.fd, file)
+                .map_err(|_| RedirectError::BadDescriptor(redirect.fd))?;
+        }
+        Ok(())
+    }
+
+    /// Hands the table's descriptors to `command`: the standard three through
+    /// `Stdio`, anything higher or closed by `dup2`/`close` in the child.
+    fn configure(&self, command: &mut Command) -> io::Result<()> {
+        if let Some(Some(file)) = self.fds.get(&0) {
+            command.stdin(file.try_clone()?);
+        }
+        if let Some(Some(file)) = self.fds.get(&1) {
+            command.stdout(file.try_clone()?);
+        }
+        if let Some(Some(file)) = self.fds.get(&2) {
+            command.stderr(file.try_clone()?);
+        }
+
+        #[cfg(unix)]
+        {
+            let mut moves = Vec::new();
+            let mut closed = Vec::new();
+            for (&fd, entry) in &self.fds {
+                match entry {
+                    Some(file) if fd > 2 => moves.push((file.as_raw_fd(), fd)),
+                    Some(_) => {}
+                    None => closed.push(fd),
+                }
+            }
+            if !moves.is_empty() || !closed.is_empty() {
+                // SAFETY: the hook only calls dup2 and close, which are
+                // async-signal-safe, on descriptors `self` keeps open until
+                // the command has been spawned.
+                unsafe {
+                    command.pre_exec(move || {
+                        for &(source, fd) in &moves {
+                            if libc::dup2(source, fd) < 0 {
+                                return Err(io::Error::last_os_error());
+                            }
+                        }
+                        for &fd in &closed {
+                            libc::close(fd);
+                        }
+                        Ok(())
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Puts the table's standard input, output and error in place of the
+    /// shell's own until the returned guard is dropped.
+    #[cfg(unix)]
+    fn install(&self) -> io::Result<SavedStreams> {
+        let _ = io::stdout().flush();
+        let mut streams = SavedStreams { saved: Vec::new() };
+        for fd in 0..=2 {
+            let Some(entry) = self.fds.get(&fd) else {
+                continue;
+            };
+            streams.saved.push((fd, duplicate_fd(fd).ok()));
+            // SAFETY: dup2 and close only replace the standard descriptor
+            // `fd`, which the guard puts back.
+            let replaced = unsafe {
+                match entry {
+                    Some(file) => libc::dup2(file.as_raw_fd(), fd),
+                    None => libc::close(fd),
+                }
+            };
+            if replaced < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(streams)
+    }
+
+    #[cfg(not(unix))]
+    fn install(&self) -> io::Result<SavedStreams> {
+        Ok(SavedStreams { saved: Vec::new() })
+    }
+
+    /// Closes every descriptor above 2 that the table does not hold.
+    #[cfg(unix)]
+    fn close_others(&self) {
+        let keep: Vec<i32> = self
+            .fds
+            .values()
+            .flatten()
+            .map(|file| file.as_raw_fd())
+            .collect();
+        let open: Vec<i32> = fs::read_dir("/dev/fd")
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect();
+        for fd in open {
+            if fd > 2 && !keep.contains(&fd) {
+                // SAFETY: nothing in this process uses `fd` any more.
+                unsafe {
+                    libc::close(fd);
+                }
+            }
+        }
+    }
+
+    fn write(&self, fd: i32, bytes: &[u8]) -> io::Result<()> {
+        match self.fds.get(&fd) {
+            Some(Some(file)) => {
+                let mut file = file;
+                file.write_all(bytes)
+            }
+            None if fd == 1 => {
+                let mut stdout = io::stdout();
+                stdout.write_all(bytes)?;
+                stdout.flush()
+            }
+            None if fd == 2 => {
+                let mut stderr = io::stderr();
+                stderr.write_all(bytes)?;
+                stderr.flush()
+            }
+            _ => Err(io::Error::other("Bad file descriptor")),
+        }
+    }
+}
+
+/// The shell's own standard descriptors, put back when dropped after
+/// [`FdTable::install`] replaced them.
+struct SavedStreams {
+    saved: Vec<(i32, Option<fs::File>)>,
+}
+
+impl Drop for SavedStreams {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        #[cfg(unix)]
+        for (fd, saved) in self.saved.drain(..).rev() {
+            // SAFETY: dup2 and close only touch the standard descriptor `fd`,
+            // which `install` replaced.
+            unsafe {
+                match saved {
+                    Some(file) => libc::dup2(file.as_raw_fd(), fd),
+                    None => libc::close(fd),
+                };
+            }
+        }
+    }
+}
+
+enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+fn write_bytes_output(bytes: &[u8], stream: OutputStream, fds: &FdTable) {
+    let fd = match stream {
+        OutputStream::Stdout => 1,
+        OutputStream::Stderr => 2,
+    };
+    let _ = fds.write(fd, bytes);
+}
+
+fn write_output(text: &str, stream: OutputStream, fds: &FdTable) {
+    write_bytes_output(text.as_bytes(), stream, fds);
+}
+
+/// Writes a builtin's output through `fds` and returns its status, or 1 when
+/// the output could not be written.
+fn write_command_result(cmd: &str, result: &CommandResult, fds: &FdTable) -> i32 {
+    if !result.stdout.is_empty()
+        && let Err(source) = fds.write(1, &result.stdout)
+    {
+        // A reader that went away early is not an error, just like a process
+        // killed by SIGPIPE.
+        if source.kind() == io::ErrorKind::BrokenPipe {
+            return STATUS_BROKEN_PIPE;
+        }
+        let err = RedirectError::Write {
+            command: cmd.to_string(),
+            source,
+        };
+        return report_error(&err.to_string());
+    }
+    if !result.stderr.is_empty() {
+        write_bytes_output(&result.stderr, OutputStream::Stderr, fds);
+    }
+    result.status
+}
+
+fn report_error(message: &str) -> i32 {
+    write_output(
+        &format!("shell: {message}\n"),
+        OutputStream::Stderr,
+        &FdTable::default(),
+    );
+    1
+}
+
+/// A pipeline stage that has been started: an external process, a builtin
+/// running on its own thread or in a forked copy of the shell, or one that
+/// failed before it could start.
+enum StageHandle {
+    Process(Child),
+    Builtin(thread::JoinHandle<i32>),
+    #[cfg(unix)]
+    Forked(i32),
+    Finished(i32),
+}
+
+impl StageHandle {
+    /// The ID of the stage's process, if it has one.
+    fn pid(&self) -> Option<i32> {
+        match self {
+            StageHandle::Process(child) => Some(child.id() as i32),
+            #[cfg(unix)]
+            StageHandle::Forked(pid) => Some(*pid),
+            _ => None,
+        }
+    }
+
+    fn wait(self) -> i32 {
+        match self {
+            StageHandle::Process(mut child) => match child.wait() {
+                Ok(exit) => exit_status_code(exit),
+                Err(_) => 1,
+            },
+            StageHandle::Builtin(handle) => handle.join().unwrap_or(1),
+            #[cfg(unix)]
+            StageHandle::Forked(pid) => {
+                let mut raw = 0;
+                // SAFETY: waitpid only writes the status of our own child to `raw`.
+                if unsafe { libc::waitpid(pid, &mut raw, 0) } == pid {
+                    exit_status_code(ExitStatus::from_raw(raw))
+                } else {
+                    1
+                }
+            }
+            StageHandle::Finished(status) => status,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum JobState {
+    Running,
+    Stopped,
+    Done(i32),
+}
+
+#[derive(Clone)]
+struct Job {
+    id: usize,
+    pgid: i32,
+    /// Each process of the job with its exit status once it has been reaped.
+    processes: Vec<(i32, Option<i32>)>,
+    command: String,
+    state: JobState,
+}
+
+impl Job {
+    fn state_text(&self) -> String {
+        match self.state {
+            JobState::Running => "Running".to_string(),
+            JobState::Stopped => "Stopped".to_string(),
+            JobState::Done(0) => "Done".to_string(),
+            JobState::Done(code) => format!("Exit {code}"),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(self.state, JobState::Done(_))
+    }
+
+    /// The exit status of process `pid`, or 0 if it has not been reaped.
+    fn exit_status(&self, pid: i32) -> i32 {
+        self.processes
+            .iter()
+            .find(|(p, _)| *p == pid)
+            .and_then(|(_, status)| *status)
+            .unwrap_or(0)
+    }
+
+    /// Reaps whichever processes of the job have finished, waiting for all
+    /// of them when `block` is set. A process that stops marks the whole job
+    /// stopped and ends the blocking wait.
+    #[cfg(unix)]
+    fn update(&mut self, block: bool) {
+        let mut flags = libc::WUNTRACED | if block { 0 } else { libc::WNOHANG };
+        for (pid, status) in &mut self.processes {
+            if status.is_some() {
+                continue;
+            }
+            let mut raw = 0;
+            // SAFETY: waitpid only writes the status of our own child to `raw`.
+            let reaped = unsafe { libc::waitpid(*pid, &mut raw, flags) };
+            if reaped == *pid && libc::WIFSTOPPED(raw) {
+                // The rest of the process group got the same signal.
+                self.state = JobState::Stopped;
+                flags |= libc::WNOHANG;
+            } else if reaped == *pid {
+                *status = Some(exit_status_code(ExitStatus::from_raw(raw)));
+            } else if reaped < 0 {
+                *status = Some(STATUS_NOT_FOUND);
+            }
+        }
+
+        if self.processes.iter().all(|(_, status)| status.is_some()) {
+            let code = self.processes.last().and_then(|(_, status)| *status);
+            self.state = JobState::Done(code.unwrap_or(0));
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn update(&mut self, _block: bool) {
+        self.state = JobState::Done(0);
+    }
+
+    /// Continues the job's process group and marks it running again.
+    #[cfg(unix)]
+    fn resume(&mut self) {
+        // SAFETY: killpg only delivers SIGCONT to the job's process group.
+        unsafe {
+            libc::killpg(self.pgid, libc::SIGCONT);
+        }
+        self.state = JobState::Running;
+    }
+
+    #[cfg(not(unix))]
+    fn resume(&mut self) {
+        self.state = JobState::Running;
+    }
+}
+
+/// Jobs started in the background, along with the current (`%+`) and
+/// previous (`%-`) job that job specs without a number refer to.
+#[derive(Clone, Default)]
+struct JobTable {
+    jobs: Vec<Job>,
+    current: Option<usize>,
+    previous: Option<usize>,
+}
+
+impl JobTable {
+    fn add(&mut self, pgid: i32, pids: Vec<i32>, command: String) -> usize {
+        self.insert(Job {
+            id: 0,
+            pgid,
+            processes: pids.into_iter().map(|pid| (pid, None)).collect(),
+            command,
+            state: JobState::Running,
+        })
+    }
+
+    /// Numbers `job` after the newest job and makes it the current one.
+    fn insert(&mut self, mut job: Job) -> usize {
+        let id = self.jobs.last().map_or(1, |job| job.id + 1);
+        job.id = id;
+        self.jobs.push(job);
+        self.make_current(id);
+        id
+    }
+
+    fn make_current(&mut self, id: usize) {
+        if self.current != Some(id) {
+            self.previous = self.current;
+            self.current = Some(id);
+        }
+    }
+
+    fn get_mut(&mut self, id: usize) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    fn remove(&mut self, id: usize) -> Option<Job> {
+        let idx = self.jobs.iter().position(|job| job.id == id)?;
+        let job = self.jobs.remove(idx);
+        if self.previous == Some(id) {
+            self.previous = None;
+        }
+        if self.current == Some(id) {
+            self.current = self.previous.take();
+        }
+        if self.current.is_none() {
+            self.current = self.latest_except(None);
+        }
+        if self.previous.is_none() {
+            self.previous = self.latest_except(self.current);
+        }
+        Some(job)
+    }
+
+    fn latest_except(&self, skip: Option<usize>) -> Option<usize> {
+        self.jobs
+            .iter()
+            .rev()
+            .map(|job| job.id)
+            .find(|id| Some(*id) != skip)
+    }
+
+    fn marker(&self, id: usize) -> char {
+        if self.current == Some(id) {
+            '+'
+        } else if self.previous == Some(id) {
+            '-'
+        } else {
+            ' '
+        }
+    }
+
+    /// Resolves a job spec (`%n`, `%+`, `%%`, `%-`, `%prefix`, `%?text` or a
+    /// process id) to a job id, defaulting to the current job.
+    fn resolve(&self, spec: Option<&str>) -> Result<usize, String> {
+        let Some(spec) = spec else {
+            return self
+                .current
+                .ok_or_else(|| "current: no such job".to_string());
+        };
+        let not_found = || format!("{spec}: no such job");
+
+        let Some(rest) = spec.strip_prefix('%') else {
+            let pid = spec.parse::<i32>().map_err(|_| not_found())?;
+            return self
+                .jobs
+                .iter()
+                .find(|job| job.processes.iter().any(|(p, _)| *p == pid))
+                .map(|job| job.id)
+                .ok_or_else(not_found);
+        };
+
+        let matches: Vec<usize> = match rest {
+            "" | "+" | "%" => self.current.into_iter().collect(),
+            "-" => self.previous.into_iter().collect(),
+            _ if rest.chars().all(|c| c.is_ascii_digit()) => self
+                .jobs
+                .iter()
+                .filter(|job| rest.parse() == Ok(job.id))
+                .map(|job| job.id)
+                .collect(),
+            _ => self
+                .jobs
+                .iter()
+                .filter(|job| match rest.strip_prefix('?') {
+                    Some(text) => job.command.contains(text),
+                    None => job.command.starts_with(rest),
+                })
+                .map(|job| job.id)
+                .collect(),
+        };
+
+        match matches.as_slice() {
+            [id] => Ok(*id),
+            [] => Err(not_found()),
+            _ => Err(format!("{spec}: ambiguous job spec")),
+        }
+    }
+
+    fn describe(&self, job: &Job, long: bool) -> String {
+        let marker = self.marker(job.id);
+        let suffix = if job.state == JobState::Running {
+            " &"
+        } else {
+            ""
+        };
+        let state = job.state_text();
+        if long {
+            format!(
+                "[{}]{marker} {} {state:<24}{}{suffix}\n",
+                job.id, job.pgid, job.command
+            )
+        } else {
+            format!("[{}]{marker}  {state:<24}{}{suffix}\n", job.id, job.command)
+        }
+    }
+}
+
+/// Signals known by name to `trap` and `kill`, in the order `trap -l` lists them.
+#[cfg(unix)]
+const SIGNALS: [(&str, i32); 29] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+    ("SYS", libc::SIGSYS),
+];
+
+#[cfg(not(unix))]
+const SIGNALS: [(&str, i32); 2] = [("INT", 2), ("TERM", 15)];
+
+/// Conditions `trap` accepts besides real signals.
+const PSEUDO_SIGNALS: [&str; 4] = ["EXIT", "DEBUG", "ERR", "RETURN"];
+
+/// Signals that arrived for a trap handler and have not been handled yet,
+/// one bit per signal number.
+static PENDING_SIGNALS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(unix)]
+extern "C" fn record_signal(signal: libc::c_int) {
+    PENDING_SIGNALS.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// Looks up a signal by name, with or without the `SIG` prefix, or by
+/// number.
+fn find_signal(spec: &str) -> Option<(&'static str, i32)> {
+    if let Ok(number) = spec.parse::<i32>() {
+        return SIGNALS.into_iter().find(|(_, signal)| *signal == number);
+    }
+    let upper = spec.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SIGNALS.into_iter().find(|(known, _)| *known == name)
+}
+
+/// Parses the signal operand of `kill`, where 0 only checks that the target
+/// exists.
+fn kill_signal(spec: &str) -> Option<i32> {
+    if spec == "0" {
+        return Some(0);
+    }
+    find_signal(spec).map(|(_, signal)| signal)
+}
+
+/// Sends `signal` to process `pid`, or to the process group `pid` leads.
+#[cfg(unix)]
+fn send_signal(pid: i32, signal: i32, group: bool) -> io::Result<()> {
+    // SAFETY: kill and killpg take plain integers and report failure via errno.
+    let rc = unsafe {
+        if group {
+            libc::killpg(pid, signal)
+        } else {
+            libc::kill(pid, signal)
+        }
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn send_signal(_pid: i32, _signal: i32, _group: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "signals are not supported on this platform",
+    ))
+}
+
+/// Resolves a `trap` condition to its canonical name; 0 stands for `EXIT`.
+fn trap_condition(spec: &str) -> Option<&'static str> {
+    if spec == "0" {
+        return Some("EXIT");
+    }
+    let upper = spec.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    PSEUDO_SIGNALS
+        .into_iter()
+        .find(|pseudo| *pseudo == name)
+        .or_else(|| find_signal(spec).map(|(name, _)| name))
+}
+
+/// Formats the signal table as `trap -l` prints it, five to a line.
+fn signal_list() -> String {
+    let mut signals = SIGNALS.to_vec();
+    signals.sort_by_key(|(_, signal)| *signal);
+    let mut out = String::new();
+    for (idx, (name, signal)) in signals.iter().enumerate() {
+        out.push_str(&format!("{signal:2}) SIG{name}"));
+        out.push(if idx % 5 == 4 || idx + 1 == signals.len() {
+            '\n'
+        } else {
+            '\t'
+        });
+    }
+    out
+}
+
+#[derive(Clone)]
+struct Shell {
+    history: Vec<String>,
+    histfile: Option<PathBuf>,
+    history_last_persisted: usize,
+    last_status: i32,
+    exit_requested: bool,
+    arg0: String,
+    positional: Vec<String>,
+    last_background_pid: Option<u32>,
+    variables: HashMap<String, Variable>,
+    substitution_status: Option<i32>,
+    options: ShellOptions,
+    jobs: JobTable,
+    /// Whether commands come from a user at a terminal rather than a script
+    /// or a subshell.
+    interactive: bool,
+    #[cfg(unix)]
+    job_control: Option<JobControl>,
+    /// Trap actions by condition name; an empty action ignores the signal.
+    traps: HashMap<&'static str, String>,
+    in_trap: bool,
+    commands: CommandTable,
+}
+
+impl Shell {
+    fn new() -> Self {
+        let histfile = env::var_os("HISTFILE").map(PathBuf::from);
+        let history = if let Some(path) = histfile.as_ref() {
+            read_history_file(path).unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        Self {
+            history_last_persisted: history.len(),
+            history,
+            histfile,
+            last_status: 0,
+            exit_requested: false,
+            arg0: env::args().next().unwrap_or_else(|| "shell".to_string()),
+            positional: Vec::new(),
+            last_background_pid: None,
+            variables: env::vars()
+                .map(|(name, value)| {
+                    let variable = Variable {
+                        value,
+                        exported: true,
+                        readonly: false,
+                    };
+                    (name, variable)
+                })
+                .collect(),
+            substitution_status: None,
+            options: ShellOptions::default(),
+            jobs: JobTable::default(),
+            interactive: false,
+            #[cfg(unix)]
+            job_control: None,
+            traps: HashMap::new(),
+            in_trap: false,
+            commands: CommandTable::default(),
+        }
+    }
+
+    /// Resets the command table if `PATH` changed since it was last used.
+    fn refresh_command_table(&mut self) {
+        let path = self.variables.get("PATH").map(|var| var.value.as_str());
+        self.commands.set_path(path);
+    }
+
+    fn command_table(&mut self) -> &mut CommandTable {
+        self.refresh_command_table();
+        &mut self.commands
+    }
+
+    fn variable(&self, name: &str) -> Option<&str> {
+        self.variables.get(name).map(|var| var.value.as_str())
+    }
+
+    fn set_variable(&mut self, name: &str, value: String) -> Result<(), String> {
+        match self.variables.get_mut(name) {
+            Some(var) if var.readonly => Err(format!("{name}: readonly variable")),
+            Some(var) => {
+                var.value = value;
+                Ok(())
+            }
+            None => {
+                let variable = Variable {
+                    value,
+                    exported: false,
+                    readonly: false,
+                };
+                self.variables.insert(name.to_string(), variable);
+                Ok(())
+            }
+        }
+    }
+
+    fn exported_env(&self, assignments: &[(String, String)]) -> Vec<(String, String)> {
+        let mut env: HashMap<&str, &str> = self
+            .variables
+            .iter()
+            .filter(|(_, var)| var.exported)
+            .map(|(name, var)| (name.as_str(), var.value.as_str()))
+            .collect();
+        for (name, value) in assignments {
+            env.insert(name, value);
+        }
+        env.into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn ifs(&self) -> String {
+        self.variable("IFS").unwrap_or(" \t\n").to_string()
+    }
+
+    fn lookup_parameter(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "$" => Some(std::process::id().to_string()),
+            "!" => self.last_background_pid.map(|pid| pid.to_string()),
+            "#" => Some(self.positional.len().to_string()),
+            "0" => Some(self.arg0.clone()),
+            _ if name.chars().all(|c| c.is_ascii_digit()) => {
+                let index = name.parse::<usize>().ok()?;
+                self.positional.get(index.checked_sub(1)?).cloned()
+            }
+            "@" | "*" => Some(self.positional.join(" ")),
+            _ => self.variable(name).map(str::to_string),
+        }
+    }
+
+    fn expand_operand(&mut self, operand: &str) -> Result<Vec<(String, bool)>, String> {
+        self.expand_parts(parse_word(operand).parts)
+    }
+
+    fn expand_parts(&mut self, parts: Vec<WordPart>) -> Result<Vec<(String, bool)>, String> {
+        let mut segments = Vec::new();
+        for part in parts {
+            match part {
+                WordPart::Text { text, quoted } => segments.push((text, quoted)),
+                WordPart::Param { expr, quoted } => {
+                    segments.push((self.expand_parameter(&expr)?, quoted));
+                }
+                WordPart::Command { source, quoted } => {
+                    segments.push((self.command_substitution(&source), quoted));
+                }
+            }
+        }
+        Ok(segments)
+    }
+
+    fn expand_operand_value(&mut self, operand: &str) -> Result<String, String> {
+        let segments = self.expand_operand(operand)?;
+        Ok(segments.into_iter().map(|(text, _)| text).collect())
+    }
+
+    fn expand_operand_pattern(&mut self, operand: &str) -> Result<String, String> {
+        let segments = self.expand_operand(operand)?;
+        Ok(segments
+            .into_iter()
+            .map(|(text, quoted)| if quoted { escape_glob(&text) } else { text })
+            .collect())
+    }
+
+    fn expand_parameter(&mut self, expr: &str) -> Result<String, String> {
+        let Some((length, name, rest)) = split_parameter_expr(expr) else {
+            return Err(format!("${{{expr}}}: bad substitution"));
+        };
+        let value = self.lookup_parameter(name);
+
+        if length {
+            let count = match name {
+                "@" | "*" => self.positional.len(),
+                _ => value.unwrap_or_default().chars().count(),
+            };
+            return Ok(count.to_string());
+        }
+        if rest.is_empty() {
+            return Ok(value.unwrap_or_default());
+        }
+
+        const OPERATORS: [&str; 16] = [
+            ":-", ":=", ":?", ":+", "-", "=", "?", "+", "##", "#", "%%", "%", "//", "/#", "/%", "/",
+        ];
+        let Some(op) = OPERATORS.into_iter().find(|op| rest.starts_with(op)) else {
+            return Err(format!("${{{expr}}}: bad substitution"));
+        };
+        let operand = &rest[op.len()..];
+        let is_set = value
+            .as_ref()
+            .is_some_and(|v| !op.starts_with(':') || !v.is_empty());
+
+        match op.trim_start_matches(':') {
+            "-" if is_set => Ok(value.unwrap_or_default()),
+            "-" => self.expand_operand_value(operand),
+            "=" if is_set => Ok(value.unwrap_or_default()),
+            "=" => {
+                if !is_valid_name(name) {
+                    return Err(format!("${name}: cannot assign in this way"));
+                }
+                let assigned = self.expand_operand_value(operand)?;
+                self.set_variable(name, assigned.clone())?;
+                Ok(assigned)
+            }
+            "?" if is_set => Ok(value.unwrap_or_default()),
+            "?" => {
+                let message = self.expand_operand_value(operand)?;
+                if message.is_empty() {
+                    Err(format!("{name}: parameter null or not set"))
+                } else {
+                    Err(format!("{name}: {message}"))
+                }
+            }
+            "+" if is_set => self.expand_operand_value(operand),
+            "+" => Ok(String::new()),
+            "#" | "##" | "%" | "%%" => {
+                let pattern = self.expand_operand_pattern(operand)?;
+                let value = value.unwrap_or_default();
+                Ok(strip_pattern(
+                    &value,
+                    &pattern,
+                    op.starts_with('#'),
+                    op.len() == 2,
+                ))
+            }
+            _ => {
+                let (pattern, replacement) = split_substitution(operand);
+                let pattern = self.expand_operand_pattern(pattern)?;
+                let replacement = self.expand_operand_value(replacement)?;
+                let anchor = op.chars().nth(1).filter(|c| matches!(c, '#' | '%'));
+                Ok(replace_pattern(
+                    &value.unwrap_or_default(),
+                    &pattern,
+                    &replacement,
+                    anchor,
+                    op == "//",
+                ))
+            }
+        }
+    }
+
+    fn tilde_replacement(&self, prefix: &str) -> Option<String> {
+        match prefix {
+            "" => self
+                .variable("HOME")
+                .map(str::to_string)
+                .or_else(current_user_home_dir),
+            "+" => self.variable("PWD").map(str::to_string),
+            "-" => self.variable("OLDPWD").map(str::to_string),
+            user => user_home_dir(user),
+        }
+    }
+
+    /// Replaces an unquoted `~prefix` at the start of `parts`, where the
+    /// prefix runs up to the first `/` (or `:` inside assignments).
+    fn expand_leading_tilde(&self, mut parts: Vec<WordPart>, in_assignment: bool) -> Vec<WordPart> {
+        let Some(WordPart::Text {
+            text,
+            quoted: false,
+        }) = parts.first()
+        else {
+            return parts;
+        };
+        let Some(rest) = text.strip_prefix('~') else {
+            return parts;
+        };
+
+        let end = rest.find(|c| c == '/' || (in_assignment && c == ':'));
+        let (prefix, tail) = match end {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None if parts.len() == 1 => (rest, ""),
+            None => return parts,
+        };
+        let Some(home) = self.tilde_replacement(prefix) else {
+            return parts;
+        };
+
+        let tail = tail.to_string();
+        let mut expanded = vec![WordPart::Text {
+            text: home,
+            quoted: true,
+        }];
+        if !tail.is_empty() {
+            expanded.push(WordPart::Text {
+                text: tail,
+                quoted: false,
+            });
+        }
+        expanded.extend(parts.drain(1..));
+        expanded
+    }
+
+    /// Applies tilde expansion to an assignment value, both at its start and
+    /// after every unquoted `:` as in `PATH=~/bin:~/.cargo/bin`.
+    fn expand_assignment_tildes(&self, parts: Vec<WordPart>) -> Vec<WordPart> {
+        let mut segments = vec![Vec::new()];
+        for part in parts {
+            match part {
+                WordPart::Text {
+                    text,
+                    quoted: false,
+                } if text.contains(':') => {
+                    for (idx, piece) in text.split(':').enumerate() {
+                        if idx > 0 {
+                            segments.push(Vec::new());
+                        }
+                        if !piece.is_empty()
+                            && let Some(segment) = segments.last_mut()
+                        {
+                            segment.push(WordPart::Text {
+                                text: piece.to_string(),
+                                quoted: false,
+                            });
+                        }
+                    }
+                }
+                other => {
+                    if let Some(segment) = segments.last_mut() {
+                        segment.push(other);
+                    }
+                }
+            }
+        }
+
+        let mut expanded = Vec::new();
+        for (idx, segment) in segments.into_iter().enumerate() {
+            if idx > 0 {
+                expanded.push(WordPart::Text {
+                    text: ":".to_string(),
+                    quoted: false,
+                });
+            }
+            expanded.extend(self.expand_leading_tilde(segment, true));
+        }
+        expanded
+    }
+
+    fn expand_word(&mut self, token: ParsedToken) -> Result<Vec<ParsedToken>, String> {
+        if token.operator {
+            return Ok(vec![token]);
+        }
+
+        let ifs = self.ifs();
+        let mut fields = FieldBuilder::default();
+        for part in self.expand_leading_tilde(token.parts, false) {
+            match part {
+                WordPart::Text { text, quoted } => fields.push_text(&text, quoted),
+                WordPart::Param { expr, quoted } if quoted && expr == "*" => {
+                    let separator = ifs.chars().next().map(String::from).unwrap_or_default();
+                    fields.push_text(&self.positional.join(&separator), true);
+                }
+                WordPart::Param { expr, quoted } if expr == "@" || expr == "*" => {
+                    for (idx, value) in self.positional.iter().enumerate() {
+                        if idx > 0 {
+                            fields.end_field();
+                        }
+                        if quoted {
+                            fields.push_text(value, true);
+                        } else {
+                            fields.push_split(value, &ifs);
+                        }
+                    }
+                }
+                WordPart::Param { expr, quoted } => {
+                    let value = self.expand_parameter(&expr)?;
+                    if quoted {
+                        fields.push_text(&value, true);
+                    } else {
+                        fields.push_split(&value, &ifs);
+                    }
+                }
+                WordPart::Command { source, quoted } => {
+                    let value = self.command_substitution(&source);
+                    if quoted {
+                        fields.push_text(&value, true);
+                    } else {
+                        fields.push_split(&value, &ifs);
+                    }
+                }
+            }
+        }
+        let mut expanded = Vec::new();
+        for field in fields.finish() {
+            expanded.extend(self.expand_pathname(field)?);
+        }
+        Ok(expanded)
+    }
+
+    fn expand_pathname(&self, field: ParsedToken) -> Result<Vec<ParsedToken>, String> {
+        let Some(pattern) = field.glob_pattern() else {
+            return Ok(vec![field]);
+        };
+
+        let matches = expand_glob(&pattern, &self.options);
+        if !matches.is_empty() {
+            return Ok(matches.into_iter().map(ParsedToken::literal).collect());
+        }
+        if self.options.failglob {
+            return Err(format!("no match: {}", field.text));
+        }
+        if self.options.nullglob {
+            return Ok(Vec::new());
+        }
+        Ok(vec![field])
+    }
+
+    fn expand_tokens(&mut self, tokens: Vec<ParsedToken>) -> Result<Vec<ParsedToken>, String> {
+        let mut expanded = Vec::with_capacity(tokens.len());
+        for token in tokens.into_iter().flat_map(expand_braces) {
+            expanded.extend(self.expand_word(token)?);
+        }
+        Ok(expanded)
+    }
+
+    fn build_stage(&mut self, segment: Vec<ParsedToken>) -> Result<PipelineStage, String> {
+        // Without a command the assignments apply to the shell itself, each
+        // one before the next is expanded, so `a=1 b=$a` sees the new `a`.
+        let assign_now = !has_command_word(&segment);
+        let mut assignments = Vec::new();
+        let mut words = segment.into_iter().peekable();
+        while let Some((name, parts)) = words.peek().and_then(split_assignment) {
+            words.next();
+            let parts = self.expand_assignment_tildes(parts);
+            let value = self.expand_parts(parts)?;
+            let value = value.into_iter().map(|(text, _)| text).collect();
+            if assign_now {
+                self.set_variable(&name, value)?;
+            } else {
+                assignments.push((name, value));
+            }
+        }
+
+        let mut tokens = Vec::new();
+        while let Some(token) = words.next() {
+            let op = if token.operator {
+                split_redirect_operator(&token.text).map(|(_, op)| op.to_string())
+            } else {
+                None
+            };
+            if let Some(op) = op
+                && let Some(target) = words.next_if(|next| !next.operator)
+            {
+                tokens.push(token);
+                if matches!(op.as_str(), "<<" | "<<-" | "<<<") {
+                    tokens.push(self.expand_literal_target(target)?);
+                } else {
+                    tokens.push(self.expand_redirect_target(target)?);
+                }
+                continue;
+            }
+            tokens.extend(self.expand_tokens(vec![token])?);
+        }
+        let (tokens, redirects) = parse_redirections(tokens).map_err(|err| err.to_string())?;
+        let env = self.exported_env(&assignments);
+        Ok(PipelineStage {
+            cmd: tokens.first().cloned().unwrap_or_default(),
+            args: tokens.get(1..).unwrap_or_default().to_vec(),
+            redirects,
+            assignments,
+            env,
+        })
+    }
+
+    /// Expands a here-string or here-document body to a single word, with
+    /// neither field splitting nor pathname expansion.
+    fn expand_literal_target(&mut self, token: ParsedToken) -> Result<ParsedToken, String> {
+        let parts = self.expand_leading_tilde(token.parts, false);
+        let text = self
+            .expand_parts(parts)?
+            .into_iter()
+            .map(|(text, _)| text)
+            .collect();
+        Ok(ParsedToken::literal(text))
+    }
+
+    /// Expands the file name or descriptor after a redirection operator,
+    /// which must come out as exactly one word.
+    fn expand_redirect_target(&mut self, token: ParsedToken) -> Result<ParsedToken, String> {
+        let original = token.text.clone();
+        let mut fields = self.expand_word(token)?;
+        if fields.len() != 1 {
+            return Err(RedirectError::Ambiguous(original).to_string());
+        }
+        Ok(fields.remove(0))
+    }
+
+    fn report_expansion_error(&mut self, message: &str) {
+        self.last_status = report_error(message);
+    }
+
+    /// Runs `source` in a copy of the shell and returns its standard output
+    /// with trailing newlines removed.
+    fn command_substitution(&mut self, source: &str) -> String {
+        let mut subshell = self.clone();
+        subshell.interactive = false;
+        // The commands stay in the shell's process group, but still get the
+        // terminal modes of a job while they run.
+        #[cfg(unix)]
+        let control = subshell.job_control.take();
+        #[cfg(unix)]
+        if let Some(control) = &control {
+            control.prepare_terminal();
+        }
+        subshell.traps.retain(|_, action| action.is_empty());
+        let cwd = env::current_dir().ok();
+        let output = capture_stdout(|| subshell.execute_source(source)).unwrap_or_default();
+        #[cfg(unix)]
+        if let Some(control) = &control {
+            control.reclaim_terminal();
+        }
+        if let Some(dir) = cwd {
+            let _ = env::set_current_dir(dir);
+        }
+        self.substitution_status = Some(subshell.last_status);
+
+        let mut text = String::from_utf8_lossy(&output).into_owned();
+        text.truncate(text.trim_end_matches('\n').len());
+        text
+    }
+
+    /// Runs one line of input, pulling any here-document bodies it needs
+    /// from `next_line`. Interrupting that input drops the whole command.
+    fn execute_line(
+        &mut self,
+        input: &str,
+        next_line: &mut dyn FnMut() -> io::Result<Option<String>>,
+    ) {
+        let mut tokens = parse_line(input);
+        if tokens.is_empty() {
+            return;
+        }
+        self.history.push(input.to_string());
+        match read_heredocs(&mut tokens, next_line) {
+            Ok(()) => self.execute_tokens(tokens),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => self.last_status = 130,
+            Err(err) => {
+                self.last_status =
+                    report_error(&format!("failed to read input: {}", io_error_message(&err)));
+            }
+        }
+    }
+
+    /// Runs the commands in the file at `path` in this shell.
+    fn execute_file(&mut self, path: &Path) -> io::Result<()> {
+        let source = fs::read_to_string(path)?;
+        self.execute_source(&source);
+        Ok(())
+    }
+
+    /// Runs the system-wide `/etc/shellprofile` and then `~/.profile` for a
+    /// login shell, then for an interactive one the file named by `$ENV` and
+    /// the rc file. Missing files are skipped.
+    fn run_startup_files(&mut self, invocation: &Invocation, interactive: bool) {
+        let home = self.tilde_replacement("").map(PathBuf::from);
+        let mut files = Vec::new();
+        if invocation.login && !invocation.noprofile {
+            files.push(PathBuf::from("/etc/shellprofile"));
+            files.extend(home.as_ref().map(|home| home.join(".profile")));
+        }
+        if interactive {
+            if let Some(env_file) = self.variable("ENV").map(str::to_string)
+                && let Ok(env_file) = self.expand_operand_value(&env_file)
+                && !env_file.is_empty()
+            {
+                files.push(PathBuf::from(env_file));
+            }
+            if !invocation.norc {
+                let rcfile = invocation.rcfile.clone();
+                files.extend(rcfile.or_else(|| home.map(|home| home.join(".shellrc"))));
+            }
+        }
+
+        for file in files {
+            match self.execute_file(&file) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    report_error(&format!("{}: {}", file.display(), io_error_message(&err)));
+                }
+                _ => {}
+            }
+            if self.exit_requested {
+                break;
+            }
+        }
+    }
+
+    fn execute_source(&mut self, source: &str) {
+        self.execute_lines(&mut source.lines().map(str::to_string));
+    }
+
+    /// Runs each line in turn, with here-document bodies taken from the
+    /// lines that follow.
+    fn execute_lines(&mut self, lines: &mut dyn Iterator<Item = String>) {
+        while let Some(line) = lines.next() {
+            let mut tokens = parse_line(&line);
+            let _ = read_heredocs(&mut tokens, &mut || Ok(lines.next()));
+            self.execute_tokens(tokens);
+            if self.exit_requested {
+                break;
+            }
+        }
+    }
+
+    fn execute_tokens(&mut self, tokens: Vec<ParsedToken>) {
+        let list = match split_command_list(tokens) {
+            Ok(list) => list,
+            Err(token) => {
+                write_output(
+                    &format!("shell: syntax error near unexpected token `{token}'\n"),
+                    OutputStream::Stderr,
+                    &FdTable::default(),
+                );
+                self.last_status = 2;
+                return;
+            }
+        };
+
+        for item in list {
+            if item.background {
+                self.run_in_background(item.pipelines);
+            } else {
+                self.run_and_or(item.pipelines);
+            }
+            self.run_pending_traps();
+            if self.exit_requested {
+                break;
+            }
+        }
+    }
+
+    fn run_and_or(&mut self, pipelines: Vec<(ListOperator, Vec<ParsedToken>)>) {
+        let last_index = pipelines.len().saturating_sub(1);
+        for (idx, (connector, tokens)) in pipelines.into_iter().enumerate() {
+            let should_run = match connector {
+                ListOperator::Sequence => true,
+                ListOperator::And => self.last_status == 0,
+                ListOperator::Or => self.last_status != 0,
+            };
+            if !should_run {
+                continue;
+            }
+
+            self.run_pipeline(tokens);
+            // Only the last pipeline of an `&&`/`||` list counts as failing.
+            if self.last_status != 0 && idx == last_index && !self.exit_requested {
+                self.execute_trap("ERR");
+            }
+            if self.exit_requested {
+                break;
+            }
+        }
+    }
+
+    /// Runs the action trapped for `condition`, leaving `$?` and a pending
+    /// `exit` as they were unless the action itself exits the shell.
+    fn execute_trap(&mut self, condition: &str) {
+        if self.in_trap {
+            return;
+        }
+        let Some(action) = self
+            .traps
+            .get(condition)
+            .filter(|action| !action.is_empty())
+        else {
+            return;
+        };
+        let action = action.clone();
+        let status = self.last_status;
+        let exiting = std::mem::take(&mut self.exit_requested);
+        self.in_trap = true;
+        self.execute_source(&action);
+        self.in_trap = false;
+        if !self.exit_requested {
+            self.last_status = status;
+            self.exit_requested = exiting;
+        }
+    }
+
+    /// Runs the handlers of trapped signals that arrived since the last
+    /// check. Only signals this shell has an action for are taken off the
+    /// pending set.
+    fn run_pending_traps(&mut self) {
+        if self.in_trap {
+            return;
+        }
+        let trapped = SIGNALS
+            .into_iter()
+            .filter(|(name, _)| {
+                self.traps
+                    .get(name)
+                    .is_some_and(|action| !action.is_empty())
+            })
+            .fold(0u64, |mask, (_, signal)| mask | 1 << signal);
+        let pending = PENDING_SIGNALS.fetch_and(!trapped, Ordering::SeqCst) & trapped;
+        for (name, signal) in SIGNALS {
+            if pending & 1 << signal != 0 {
+                self.execute_trap(name);
+            }
+        }
+    }
+
+    /// Runs the `EXIT` trap once, as the shell is about to exit.
+    fn run_exit_trap(&mut self) {
+        self.execute_trap("EXIT");
+        self.traps.remove("EXIT");
+    }
+
+    /// Runs `pipelines` in the background and records it as a job. A lone
+    /// pipeline of external commands is spawned directly, so the job holds
+    /// their real process IDs; anything else runs in a forked copy of the
+    /// shell.
+    #[cfg(unix)]
+    fn run_in_background(&mut self, mut pipelines: Vec<(ListOperator, Vec<ParsedToken>)>) {
+        let command = command_text(&pipelines);
+        let single = pipelines.len() == 1
+            && split_pipeline(pipelines[0].1.clone())
+                .iter()
+                .all(|segment| has_command_word(segment));
+        if !single {
+            return self.fork_background(command, move |shell| shell.run_and_or(pipelines));
+        }
+
+        let (_, tokens) = pipelines.remove(0);
+        self.execute_trap("DEBUG");
+        let mut stages = Vec::new();
+        for segment in split_pipeline(tokens) {
+            match self.build_stage(segment) {
+                Ok(stage) if stage.cmd.is_empty() => {}
+                Ok(stage) => stages.push(stage),
+                Err(message) => return self.report_expansion_error(&message),
+            }
+        }
+        if stages.iter().any(|stage| is_builtin_command(&stage.cmd)) {
+            let text = command.clone();
+            return self.fork_background(command, move |shell| {
+                shell.last_status = shell.execute_pipeline(&stages, &text);
+            });
+        }
+
+        let (handles, pgid) = if stages.is_empty() {
+            (Vec::new(), 0)
+        } else {
+            self.start_pipeline(&stages, false)
+        };
+        let pids: Vec<i32> = handles.iter().filter_map(StageHandle::pid).collect();
+        self.last_status = 0;
+        if let Some(&pid) = pids.last() {
+            self.add_background_job(pgid, pids, command);
+            self.last_background_pid = Some(pid as u32);
+        }
+    }
+
+    /// Runs `run` in a forked copy of the shell and records the copy as a
+    /// job.
+    #[cfg(unix)]
+    fn fork_background(&mut self, command: String, run: impl FnOnce(&mut Self)) {
+        let pid = match self.fork_shell(0) {
+            Ok(pid) => pid,
+            Err(err) => {
+                self.last_status = report_error(&format!("fork: {}", io_error_message(&err)));
+                return;
+            }
+        };
+        if pid == 0 {
+            run(self);
+            let _ = io::stdout().flush();
+            // SAFETY: `_exit` skips destructors such as `RawModeGuard`, which
+            // belong to the interactive parent.
+            unsafe { libc::_exit(self.last_status) }
+        }
+
+        self.add_background_job(pid, vec![pid], command);
+        self.last_background_pid = Some(pid as u32);
+        self.last_status = 0;
+    }
+
+    /// Forks a non-interactive copy of the shell that joins process group
+    /// `pgid` (a new one when 0) under job control. Returns the child's ID in
+    /// the parent and 0 in the child, which must leave through `_exit`.
+    #[cfg(unix)]
+    fn fork_shell(&mut self, pgid: i32) -> io::Result<i32> {
+        let _ = io::stdout().flush();
+        // Hold the standard stream locks across the fork so that no other
+        // thread, such as a here-document writer or a builtin stage of a
+        // stopped job, owns one in the child. glibc's fork does the same for
+        // malloc.
+        let streams = (io::stdout().lock(), io::stderr().lock());
+        // SAFETY: the child only runs shell code and leaves through `_exit`,
+        // never returning into the parent's control flow. The locks it needs
+        // that other threads might have held are taken above.
+        let pid = unsafe { libc::fork() };
+        drop(streams);
+        if pid < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if pid == 0 {
+            if self.job_control.take().is_some() {
+                // SAFETY: moves this child into the job's process group and
+                // lets it be stopped and interrupted like any other job.
+                unsafe {
+                    libc::setpgid(0, pgid);
+                    for signal in JOB_CONTROL_SIGNALS {
+                        libc::signal(signal, libc::SIG_DFL);
+                    }
+                }
+            }
+            for (name, signal) in SIGNALS {
+                if self
+                    .traps
+                    .get(name)
+                    .is_some_and(|action| !action.is_empty())
+                {
+                    self.set_signal_disposition(signal, None);
+                }
+            }
+            self.traps.retain(|_, action| action.is_empty());
+            self.jobs = JobTable::default();
+            self.interactive = false;
+            return Ok(0);
+        }
+
+        if self.job_control.is_some() {
+            // SAFETY: setpgid on our own child, mirroring the call it makes.
+            unsafe {
+                libc::setpgid(pid, if pgid == 0 { pid } else { pgid });
+            }
+        }
+        Ok(pid)
+    }
+
+    /// Runs a `source` stage of a pipeline in a forked copy of the shell, with
+    /// the stage's descriptors as its standard ones, so that the sourced
+    /// commands cannot change the shell's own state.
+    #[cfg(unix)]
+    fn fork_stage(&mut self, stage: &PipelineStage, fds: FdTable, pgid: &mut i32) -> StageHandle {
+        let pid = match self.fork_shell(*pgid) {
+            Ok(pid) => pid,
+            Err(err) => {
+                return StageHandle::Finished(report_error(&format!(
+                    "fork: {}",
+                    io_error_message(&err)
+                )));
+            }
+        };
+        if pid == 0 {
+            let status = match fds.install() {
+                Ok(streams) => {
+                    // The child never puts the shell's descriptors back. Drop
+                    // them along with the copies of other stages' pipe ends,
+                    // so that those pipes' readers still see end-of-file.
+                    std::mem::forget(streams);
+                    fds.close_others();
+                    match self.run_builtin(&stage.cmd, &stage.args, false, false) {
+                        Some(result) => write_command_result(&stage.cmd, &result, &fds),
+                        None => 0,
+                    }
+                }
+                Err(err) => report_error(&io_error_message(&err)),
+            };
+            let _ = io::stdout().flush();
+            // SAFETY: see `fork_background`.
+            unsafe { libc::_exit(status) }
+        }
+
+        if *pgid == 0 {
+            *pgid = pid;
+        }
+        StageHandle::Forked(pid)
+    }
+
+    /// Adds a job started in the background and, in an interactive shell,
+    /// announces its number and the ID of its last process.
+    #[cfg(unix)]
+    fn add_background_job(&mut self, pgid: i32, pids: Vec<i32>, command: String) {
+        let pid = pids.last().copied().unwrap_or(pgid);
+        let id = self.jobs.add(pgid, pids, command);
+        if !self.interactive {
+            return;
+        }
+        write_output(
+            &format!("[{id}] {pid}\n"),
+            OutputStream::Stderr,
+            &FdTable::default(),
+        );
+    }
+
+    #[cfg(not(unix))]
+    fn run_in_background(&mut self, pipelines: Vec<(ListOperator, Vec<ParsedToken>)>) {
+        self.run_and_or(pipelines);
+    }
+
+    fn run_pipeline(&mut self, tokens: Vec<ParsedToken>) {
+        self.execute_trap("DEBUG");
+        let command = pipeline_text(&tokens);
+        let mut pipeline_segments = split_pipeline(tokens);
+        if pipeline_segments.len() > 1 {
+            let mut stages = Vec::with_capacity(pipeline_segments.len());
+            for segment in pipeline_segments {
+                match self.build_stage(segment) {
+                    Ok(stage) if stage.cmd.is_empty() => {}
+                    Ok(stage) => stages.push(stage),
+                    Err(message) => return self.report_expansion_error(&message),
+                }
+            }
+            self.last_status = self.execute_pipeline(&stages, &command);
+            return;
+        }
+
+        let segment = pipeline_segments.pop().unwrap_or_default();
+        self.run_simple_command(segment, &command);
+    }
+
+    /// Starts every stage at once, connected by OS pipes, and returns the
+    /// status of the last one. Builtins run on a thread against a copy of the
+    /// shell so they stream through the pipeline like external commands.
+    fn execute_pipeline(&mut self, stages: &[PipelineStage], command: &str) -> i32 {
+        if stages.is_empty() {
+            return self.last_status;
+        }
+
+        #[cfg(unix)]
+        if let Some(control) = &self.job_control {
+            control.prepare_terminal();
+        }
+        let (handles, pgid) = self.start_pipeline(stages, true);
+        self.wait_foreground(handles, pgid, command)
+    }
+
+    /// Starts the stages of a pipeline and returns their handles along with
+    /// the process group they share. Only a foreground pipeline takes the
+    /// terminal.
+    fn start_pipeline(
+        &mut self,
+        stages: &[PipelineStage],
+        foreground: bool,
+    ) -> (Vec<StageHandle>, i32) {
+        let last_index = stages.len() - 1;
+        let mut handles = Vec::with_capacity(stages.len());
+        let mut pgid = 0;
+        let mut previous_reader = None;
+        for (idx, stage) in stages.iter().enumerate() {
+            let stdin = previous_reader.take();
+            let mut stdout = None;
+            if idx < last_index {
+                match pipe_files() {
+                    Ok((reader, writer)) => {
+                        stdout = Some(writer);
+                        previous_reader = Some(reader);
+                    }
+                    Err(err) => {
+                        handles.push(StageHandle::Finished(report_error(&io_error_message(&err))));
+                        break;
+                    }
+                }
+            }
+
+            let mut fds = FdTable::piped(stdin, stdout);
+            handles.push(match fds.apply(&stage.redirects, self.options.noclobber) {
+                Ok(()) => self.start_stage(stage, fds, &mut pgid, foreground),
+                Err(err) => StageHandle::Finished(report_error(&err.to_string())),
+            });
+        }
+        (handles, pgid)
+    }
+
+    /// Waits for the stages of a foreground job while its process group owns
+    /// the terminal and returns the status of the last one. Under job control
+    /// a job that is stopped instead is added to the job table.
+    fn wait_foreground(&mut self, handles: Vec<StageHandle>, pgid: i32, command: &str) -> i32 {
+        #[cfg(unix)]
+        if let Some(control) = self.job_control {
+            let status = if pgid != 0 {
+                control.give_terminal(pgid);
+                self.wait_stoppable(handles, pgid, command)
+            } else {
+                handles
+                    .into_iter()
+                    .map(StageHandle::wait)
+                    .last()
+                    .unwrap_or(0)
+            };
+            control.reclaim_terminal();
+            if status == 128 + libc::SIGINT {
+                // Start the next prompt below the `^C` the terminal echoed.
+                write_output("\n", OutputStream::Stdout, &FdTable::default());
+            }
+            return status;
+        }
+
+        let _ = (pgid, command);
+        handles
+            .into_iter()
+            .map(StageHandle::wait)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Waits for the processes of a foreground job with `WUNTRACED`, so that
+    /// Ctrl-Z hands control back to the shell.
+    #[cfg(unix)]
+    fn wait_stoppable(&mut self, handles: Vec<StageHandle>, pgid: i32, command: &str) -> i32 {
+        let processes = handles
+            .iter()
+            .filter_map(|handle| Some((handle.pid()?, None)))
+            .collect();
+        let mut job = Job {
+            id: 0,
+            pgid,
+            processes,
+            command: command.to_string(),
+            state: JobState::Running,
+        };
+        job.update(true);
+
+        if job.state == JobState::Stopped {
+            // Builtin stages are left to finish on their own threads.
+            let id = self.jobs.insert(job);
+            return self.report_stopped(id);
+        }
+        handles
+            .into_iter()
+            .map(|handle| match handle.pid() {
+                Some(pid) => job.exit_status(pid),
+                None => handle.wait(),
+            })
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Announces that job `id` was stopped and returns the status of a
+    /// command suspended by SIGTSTP.
+    #[cfg(unix)]
+    fn report_stopped(&self, id: usize) -> i32 {
+        if let Some(job) = self.jobs.jobs.iter().find(|job| job.id == id) {
+            let line = format!("\n{}", self.jobs.describe(job, false));
+            write_output(&line, OutputStream::Stderr, &FdTable::default());
+        }
+        128 + libc::SIGTSTP
+    }
+
+    /// Has a spawned command join process group `pgid` (a new one when 0) and,
+    /// in the foreground, take the terminal under job control, and restores
+    /// the default dispositions of the signals the shell ignores. Without job
+    /// control only SIGINT and SIGQUIT are restored.
+    #[cfg(unix)]
+    fn prepare_process(&self, command: &mut Command, pgid: i32, foreground: bool) {
+        let group = self.job_control.is_some();
+        let terminal = self
+            .job_control
+            .filter(|_| foreground)
+            .map(|control| control.fd);
+        // SAFETY: setpgid, getpgrp, tcsetpgrp and signal are all
+        // async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                if group {
+                    libc::setpgid(0, pgid);
+                }
+                if let Some(fd) = terminal {
+                    libc::tcsetpgrp(fd, libc::getpgrp());
+                }
+                for signal in JOB_CONTROL_SIGNALS {
+                    // A command outside a process group of its own could not
+                    // be resumed once stopped, so it keeps ignoring the stop
+                    // signals if the shell does.
+                    if group || matches!(signal, libc::SIGINT | libc::SIGQUIT) {
+                        libc::signal(signal, libc::SIG_DFL);
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Starts one pipeline stage. `fds` is moved in so the shell's copies of
+    /// the pipe ends are closed as soon as the stage owns them. The first
+    /// process started sets `pgid` for the rest of the pipeline.
+    fn start_stage(
+        &mut self,
+        stage: &PipelineStage,
+        fds: FdTable,
+        pgid: &mut i32,
+        foreground: bool,
+    ) -> StageHandle {
+        #[cfg(unix)]
+        if matches!(stage.cmd.as_str(), "source" | ".") {
+            return self.fork_stage(stage, fds, pgid);
+        }
+        if is_builtin_command(&stage.cmd) {
+            let mut subshell = self.clone();
+            let stage = stage.clone();
+            return StageHandle::Builtin(thread::spawn(move || {
+                match subshell.run_builtin(&stage.cmd, &stage.args, false, false) {
+                    Some(result) => write_command_result(&stage.cmd, &result, &fds),
+                    None => 0,
+                }
+            }));
+        }
+
+        let program = if stage.cmd.contains('/') {
+            if let Some(status) = report_path_error(&stage.cmd, &fds) {
+                return StageHandle::Finished(status);
+            }
+            PathBuf::from(&stage.cmd)
+        } else {
+            // A `PATH=...` prefix searches its own value and bypasses the table.
+            let found = if stage.assignments.iter().any(|(name, _)| name == "PATH") {
+                find_in_path(&stage.cmd, stage.search_path())
+            } else {
+                self.command_table().find(&stage.cmd)
+            };
+            match found {
+                Some(program) => program,
+                None => return StageHandle::Finished(report_command_not_found(&stage.cmd, &fds)),
+            }
+        };
+        let mut command = stage.command(&program);
+        #[cfg(unix)]
+        self.prepare_process(&mut command, *pgid, foreground);
+        #[cfg(not(unix))]
+        let _ = foreground;
+        match fds.configure(&mut command).and_then(|()| command.spawn()) {
+            Ok(child) => {
+                let pid = child.id() as i32;
+                if *pgid == 0 {
+                    *pgid = pid;
+                }
+                // Set the group from the parent too, so it is in place before
+                // the terminal is handed over regardless of scheduling.
+                #[cfg(unix)]
+                if self.job_control.is_some() {
+                    // SAFETY: setpgid on our own freshly spawned child.
+                    unsafe {
+                        libc::setpgid(pid, *pgid);
+                    }
+                }
+                StageHandle::Process(child)
+            }
+            Err(err) => StageHandle::Finished(report_spawn_error(&stage.cmd, &err, &fds)),
+        }
+    }
+
+    fn run_simple_command(&mut self, segment: Vec<ParsedToken>, command: &str) {
+        self.substitution_status = None;
+        let stage = match self.build_stage(segment) {
+            Ok(stage) => stage,
+            Err(message) => return self.report_expansion_error(&message),
+        };
+        let mut fds = FdTable::default();
+        if let Err(err) = fds.apply(&stage.redirects, self.options.noclobber) {
+            return self.report_expansion_error(&err.to_string());
+        }
+
+        if stage.cmd.is_empty() {
+            self.last_status = self.substitution_status.unwrap_or(0);
+            for (name, value) in stage.assignments {
+                if let Err(message) = self.set_variable(&name, value) {
+                    return self.report_expansion_error(&message);
+                }
+            }
+            return;
+        }
+
+        let cmd = &stage.cmd;
+        let args = &stage.args;
+        if cmd == "history" {
+            let result = run_history_command(
+                args,
+                &mut self.history,
+                self.histfile.as_ref(),
+                &mut self.history_last_persisted,
+            );
+            self.last_status = write_command_result(cmd, &result, &fds);
+            return;
+        }
+
+        // Commands run by `source` use the standard descriptors directly.
+        let streams = match cmd.as_str() {
+            "source" | "." => match fds.install() {
+                Ok(streams) => Some(streams),
+                Err(err) => return self.report_expansion_error(&io_error_message(&err)),
+            },
+            _ => None,
+        };
+        if let Some(result) = self.run_builtin(cmd, args, true, true) {
+            drop(streams);
+            self.last_status = write_command_result(cmd, &result, &fds);
+            self.exit_requested = result.should_exit;
+            return;
+        }
+
+        #[cfg(unix)]
+        if let Some(control) = &self.job_control {
+            control.prepare_terminal();
+        }
+        let mut pgid = 0;
+        let handle = self.start_stage(&stage, fds, &mut pgid, true);
+        self.last_status = self.wait_foreground(vec![handle], pgid, command);
+    }
+
+    fn run_builtin(
+        &mut self,
+        cmd: &str,
+        args: &[String],
+        allow_exit: bool,
+        apply_cd: bool,
+    ) -> Option<CommandResult> {
+        let mut result = CommandResult::default();
+
+        match cmd {
+            "exit" => {
+                result.status = match args.first() {
+                    Some(code) => match code.parse::<i32>() {
+                        Ok(code) => code & 0xff,
+                        Err(_) => {
+                            result.stderr =
+                                format!("exit: {code}: numeric argument required\n").into_bytes();
+                            2
+                        }
+                    },
+                    None => self.last_status,
+                };
+                if allow_exit {
+                    result.should_exit = true;
+                }
+            }
+            "echo" => {
+                result.stdout = format!("{}\n", args.join(" ")).into_bytes();
+            }
+            "pwd" => {
+                if let Ok(dir) = env::current_dir() {
+                    result.stdout = format!("{}\n", dir.display()).into_bytes();
+                }
+            }
+            "cd" => {
+                if apply_cd && let Some(target) = args.first() {
+                    let previous = env::current_dir().ok();
+                    if env::set_current_dir(target).is_err() {
+                        result.stderr =
+                            format!("cd: {target}: No such file or directory\n").into_bytes();
+                        result.status = 1;
+                    } else {
+                        if let Some(previous) = previous {
+                            let _ = self.set_variable("OLDPWD", previous.display().to_string());
+                        }
+                        if let Ok(current) = env::current_dir() {
+                            let _ = self.set_variable("PWD", current.display().to_string());
+                        }
+                    }
+                }
+            }
+            "type" => {
+                if let Some(query) = args.first() {
+                    if is_builtin_command(query) {
+                        result.stdout = format!("{query} is a shell builtin\n").into_bytes();
+                    } else if let Some(path) = find_in_path(query, self.variable("PATH")) {
+                        result.stdout = format!("{query} is {}\n", path.display()).into_bytes();
+                    } else {
+                        result.stdout = format!("{query}: not found\n").into_bytes();
+                        result.status = 1;
+                    }
+                }
+            }
+            "history" => {
+                let limit = args.first().and_then(|s| s.parse::<usize>().ok());
+                result.stdout = format_history(&self.history, limit);
+            }
+            "export" => self.run_export(args, &mut result),
+            "readonly" => self.run_readonly(args, &mut result),
+            "unset" => self.run_unset(args, &mut result),
+            "shopt" => self.run_shopt(args, &mut result),
+            "set" => self.run_set(args, &mut result),
+            "jobs" => self.run_jobs(args, &mut result),
+            "fg" => self.run_fg(args, &mut result),
+            "bg" => self.run_bg(args, &mut result),
+            "wait" => self.run_wait(args, &mut result),
+            "disown" => self.run_disown(args, &mut result),
+            "trap" => self.run_trap(args, &mut result),
+            "kill" => self.run_kill(args, &mut result),
+            "hash" => self.run_hash(args, &mut result),
+            "source" | "." => {
+                self.run_source(cmd, args, &mut result);
+                result.should_exit = allow_exit && self.exit_requested;
+            }
+            _ => return None,
+        }
+
+        Some(result)
+    }
+
+    /// Applies `NAME` or `NAME=value` arguments of `export`/`readonly`,
+    /// marking each variable with `mark`.
+    fn mark_variables(
+        &mut self,
+        builtin: &str,
+        args: &[String],
+        result: &mut CommandResult,
+        mark: fn(&mut Variable),
+    ) {
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            if !is_valid_name(name) {
+                result
+                    .stderr
+                    .extend(format!("{builtin}: `{arg}': not a valid identifier\n").bytes());
+                result.status = 1;
+                continue;
+            }
+
+            let current = self.variable(name).unwrap_or_default().to_string();
+            if let Err(message) = self.set_variable(name, value.unwrap_or(current)) {
+                result
+                    .stderr
+                    .extend(format!("{builtin}: {message}\n").bytes());
+                result.status = 1;
+                continue;
+            }
+            if let Some(var) = self.variables.get_mut(name) {
+                mark(var);
+            }
+        }
+    }
+
+    fn list_variables(&self, prefix: &str, filter: fn(&Variable) -> bool) -> Vec<u8> {
+        let mut names: Vec<&String> = self
+            .variables
+            .iter()
+            .filter(|(_, var)| filter(var))
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+
+        let mut out = String::new();
+        for name in names {
+            let value = &self.variables[name].value;
+            out.push_str(&format!("{prefix}{name}={}\n", quote_value(value)));
+        }
+        out.into_bytes()
+    }
+
+    fn run_export(&mut self, args: &[String], result: &mut CommandResult) {
+        match args.first().map(String::as_str) {
+            None | Some("-p") if args.len() <= 1 => {
+                result.stdout = self.list_variables("export ", |var| var.exported);
+            }
+            Some("-n") => {
+                for name in &args[1..] {
+                    if let Some(var) = self.variables.get_mut(name) {
+                        var.exported = false;
+                    }
+                }
+            }
+            _ => {
+                let names = args.strip_prefix(&["-p".to_string()]).unwrap_or(args);
+                self.mark_variables("export", names, result, |var| var.exported = true);
+            }
+        }
+    }
+
+    fn run_readonly(&mut self, args: &[String], result: &mut CommandResult) {
+        match args.first().map(String::as_str) {
+            None | Some("-p") if args.len() <= 1 => {
+                result.stdout = self.list_variables("readonly ", |var| var.readonly);
+            }
+            _ => {
+                let names = args.strip_prefix(&["-p".to_string()]).unwrap_or(args);
+                self.mark_variables("readonly", names, result, |var| var.readonly = true);
+            }
+        }
+    }
+
+    fn run_shopt(&mut self, args: &[String], result: &mut CommandResult) {
+        let mut mode = None;
+        let mut print = false;
+        let mut quiet = false;
+        let mut names = args;
+        while let Some(flag) = names.first().filter(|arg| arg.starts_with('-')) {
+            for ch in flag.chars().skip(1) {
+                match ch {
+                    's' => mode = Some(true),
+                    'u' => mode = Some(false),
+                    'p' => print = true,
+                    'q' => quiet = true,
+                    _ => {
+                        result.stderr = format!("shopt: -{ch}: invalid option\n").into_bytes();
+                        result.status = 2;
+                        return;
+                    }
+                }
+            }
+            names = &names[1..];
+        }
+
+        let selected: Vec<&str> = if names.is_empty() {
+            SHOPT_NAMES.to_vec()
+        } else {
+            names.iter().map(String::as_str).collect()
+        };
+
+        let mut out = String::new();
+        for name in selected {
+            let Some(value) = self.options.shopt_mut(name) else {
+                result
+                    .stderr
+                    .extend(format!("shopt: {name}: invalid shell option name\n").bytes());
+                result.status = 1;
+                continue;
+            };
+
+            if let Some(enable) = mode {
+                if !names.is_empty() {
+                    *value = enable;
+                    continue;
+                }
+                if *value != enable {
+                    continue;
+                }
+            }
+
+            if !*value && !names.is_empty() {
+                result.status = 1;
+            }
+            if quiet {
+                continue;
+            }
+            if print {
+                let flag = if *value { "-s" } else { "-u" };
+                out.push_str(&format!("shopt {flag} {name}\n"));
+            } else {
+                let state = if *value { "on" } else { "off" };
+                out.push_str(&format!("{name:<15}\t{state}\n"));
+            }
+        }
+        result.stdout = out.into_bytes();
+    }
+
+    fn run_set(&mut self, args: &[String], result: &mut CommandResult) {
+        if args.is_empty() {
+            result.stdout = self.list_variables("", |_| true);
+            return;
+        }
+
+        let mut idx = 0;
+        while let Some(arg) = args.get(idx) {
+            let enable = match arg.chars().next() {
+                Some('-') => true,
+                Some('+') => false,
+                _ => break,
+            };
+            idx += 1;
+            if arg == "--" || arg.len() == 1 {
+                self.positional = args[idx..].to_vec();
+                return;
+            }
+
+            for ch in arg.chars().skip(1) {
+                match ch {
+                    'C' => self.options.noclobber = enable,
+                    'o' => {
+                        let Some(name) = args.get(idx) else {
+                            let mut out = String::new();
+                            for name in SET_OPTION_NAMES {
+                                let value = self.options.set_option_mut(name).is_some_and(|v| *v);
+                                if enable {
+                                    let state = if value { "on" } else { "off" };
+                                    out.push_str(&format!("{name:<15}\t{state}\n"));
+                                } else {
+                                    let flag = if value { '-' } else { '+' };
+                                    out.push_str(&format!("set {flag}o {name}\n"));
+                                }
+                            }
+                            result.stdout = out.into_bytes();
+                            continue;
+                        };
+                        idx += 1;
+                        let Some(value) = self.options.set_option_mut(name) else {
+                            result.stderr =
+                                format!("set: {name}: invalid option name\n").into_bytes();
+                            result.status = 1;
+                            return;
+                        };
+                        *value = enable;
+                    }
+                    _ => {
+                        result.stderr = format!("set: -{ch}: invalid option\n").into_bytes();
+                        result.status = 2;
+                        return;
+                    }
+                }
+            }
+        }
+
+        if idx < args.len() {
+            self.positional = args[idx..].to_vec();
+        }
+    }
+
+    fn run_unset(&mut self, args: &[String], result: &mut CommandResult) {
+        let mut functions = false;
+        let mut names = args;
+        while let Some(flag) = names.first() {
+            match flag.as_str() {
+                "-f" => functions = true,
+                "-v" => functions = false,
+                _ => break,
+            }
+            names = &names[1..];
+        }
+        if functions {
+            // There is no function table yet, so unsetting functions never fails.
+            return;
+        }
+
+        for name in names {
+            match self.variables.get(name) {
+                Some(var) if var.readonly => {
+                    result.stderr.extend(
+                        format!("unset: {name}: cannot unset: readonly variable\n").bytes(),
+                    );
+                    result.status = 1;
+                }
+                Some(_) => {
+                    self.variables.remove(name);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Reports background jobs that stopped or finished since the last
+    /// prompt and forgets the finished ones.
+    fn notify_jobs(&mut self) {
+        let mut changed = Vec::new();
+        for job in &mut self.jobs.jobs {
+            if job.is_done() {
+                continue;
+            }
+            let before = job.state;
+            job.update(false);
+            if job.state != before {
+                changed.push(job.id);
+            }
+        }
+
+        for id in changed {
+            let Some(job) = self.jobs.jobs.iter().find(|job| job.id == id) else {
+                continue;
+            };
+            let line = self.jobs.describe(job, false);
+            write_output(&line, OutputStream::Stderr, &FdTable::default());
+            if job.is_done() {
+                self.jobs.remove(id);
+            }
+        }
+    }
+
+    fn run_jobs(&mut self, args: &[String], result: &mut CommandResult) {
+        let mut long = false;
+        let mut pids_only = false;
+        let mut specs = args;
+        while let Some(flag) = specs
+            .first()
+            .filter(|arg| arg.len() > 1 && arg.starts_with('-'))
+        {
+            for ch in flag.chars().skip(1) {
+                match ch {
+                    'l' => long = true,
+                    'p' => pids_only = true,
+                    _ => {
+                        result.stderr = format!("jobs: -{ch}: invalid option\n").into_bytes();
+                        result.status = 2;
+                        return;
+                    }
+                }
+            }
+            specs = &specs[1..];
+        }
+
+        for job in &mut self.jobs.jobs {
+            if !job.is_done() {
+                job.update(false);
+            }
+        }
+
+        let ids: Vec<usize> = if specs.is_empty() {
+            self.jobs.jobs.iter().map(|job| job.id).collect()
+        } else {
+            let mut ids = Vec::new();
+            for spec in specs {
+                match self.jobs.resolve(Some(spec)) {
+                    Ok(id) => ids.push(id),
+                    Err(message) => {
+                        result.stderr.extend(format!("jobs: {message}\n").bytes());
+                        result.status = 1;
+                    }
+                }
+            }
+            ids
+        };
+
+        let mut out = String::new();
+        for id in &ids {
+            let Some(job) = self.jobs.jobs.iter().find(|job| job.id == *id) else {
+                continue;
+            };
+            if pids_only {
+                out.push_str(&format!("{}\n", job.pgid));
+            } else {
+                out.push_str(&self.jobs.describe(job, long));
+            }
         }
-        history.push(input.clone());
+        result.stdout = out.into_bytes();
 
-        let mut pipeline_segments = split_pipeline(tokens);
-        if pipeline_segments.len() > 1 {
-            execute_pipeline(pipeline_segments, &history);
-            continue;
+        for id in ids {
+            if self.jobs.get_mut(id).is_some_and(|job| job.is_done()) {
+                self.jobs.remove(id);
+            }
         }
+    }
 
-        let segment = pipeline_segments.pop().unwrap_or_default();
-        let (tokens, redirects) = parse_redirections(segment);
-        let Some(cmd) = tokens.first().cloned() else {
-            continue;
+    fn run_fg(&mut self, args: &[String], result: &mut CommandResult) {
+        let id = match self.jobs.resolve(args.first().map(String::as_str)) {
+            Ok(id) => id,
+            Err(message) => {
+                result.stderr = format!("fg: {message}\n").into_bytes();
+                result.status = 1;
+                return;
+            }
+        };
+        let Some(job) = self.jobs.get_mut(id) else {
+            return;
         };
-        let args = tokens[1..].to_vec();
-        ensure_redirect_files(&redirects);
 
-        if cmd == "history" {
-            let result = run_history_command(
-                &args,
-                &mut history,
-                histfile.as_ref(),
-                &mut history_last_persisted,
-            );
-            if !result.stdout.is_empty() {
-                write_bytes_output(&result.stdout, OutputStream::Stdout, &redirects);
+        write_output(
+            &format!("{}\n", job.command),
+            OutputStream::Stdout,
+            &FdTable::default(),
+        );
+        #[cfg(unix)]
+        if let Some(control) = &self.job_control {
+            control.prepare_terminal();
+            control.give_terminal(job.pgid);
+        }
+        job.resume();
+        job.update(true);
+        #[cfg(unix)]
+        if let Some(control) = &self.job_control {
+            control.reclaim_terminal();
+        }
+
+        match job.state {
+            #[cfg(unix)]
+            JobState::Stopped => {
+                self.jobs.make_current(id);
+                result.status = self.report_stopped(id);
+            }
+            JobState::Done(code) => {
+                #[cfg(unix)]
+                if self.job_control.is_some() && code == 128 + libc::SIGINT {
+                    write_output("\n", OutputStream::Stdout, &FdTable::default());
+                }
+                result.status = code;
+                self.jobs.remove(id);
             }
-            if !result.stderr.is_empty() {
-                write_bytes_output(&result.stderr, OutputStream::Stderr, &redirects);
+            _ => {
+                self.jobs.remove(id);
+            }
+        }
+    }
+
+    fn run_bg(&mut self, args: &[String], result: &mut CommandResult) {
+        let specs: Vec<Option<&str>> = if args.is_empty() {
+            vec![None]
+        } else {
+            args.iter().map(|arg| Some(arg.as_str())).collect()
+        };
+
+        for spec in specs {
+            match self.jobs.resolve(spec) {
+                Ok(id) => {
+                    let marker = self.jobs.marker(id);
+                    let Some(job) = self.jobs.get_mut(id) else {
+                        continue;
+                    };
+                    if job.state != JobState::Stopped {
+                        result
+                            .stderr
+                            .extend(format!("bg: job {id} already in background\n").bytes());
+                        continue;
+                    }
+                    job.resume();
+                    result
+                        .stdout
+                        .extend(format!("[{id}]{marker} {} &\n", job.command).bytes());
+                }
+                Err(message) => {
+                    result.stderr.extend(format!("bg: {message}\n").bytes());
+                    result.status = 1;
+                }
+            }
+        }
+    }
+
+    fn run_wait(&mut self, args: &[String], result: &mut CommandResult) {
+        if args.is_empty() {
+            let ids: Vec<usize> = self.jobs.jobs.iter().map(|job| job.id).collect();
+            for id in ids {
+                if !self.wait_for_job(id, result) {
+                    return;
+                }
+            }
+            return;
+        }
+
+        for arg in args {
+            let id = match self.jobs.resolve(Some(arg)) {
+                Ok(id) => id,
+                Err(message) => {
+                    let message = if arg.starts_with('%') {
+                        format!("wait: {message}\n")
+                    } else {
+                        format!("wait: pid {arg} is not a child of this shell\n")
+                    };
+                    result.stderr.extend(message.bytes());
+                    result.status = STATUS_NOT_FOUND;
+                    continue;
+                }
+            };
+            if !self.wait_for_job(id, result) {
+                return;
+            }
+        }
+    }
+
+    /// Waits for job `id` to finish and removes it, setting `result.status`
+    /// to its exit status. A job that is or becomes stopped stays in the
+    /// table; returns false if it stopped during the wait.
+    fn wait_for_job(&mut self, id: usize, result: &mut CommandResult) -> bool {
+        let Some(job) = self.jobs.get_mut(id) else {
+            return true;
+        };
+        // A stopped process has already reported its stop, so waiting for it
+        // again would block until something else resumes it. Only pick up a
+        // stopped job that has since finished.
+        if job.state == JobState::Stopped {
+            job.update(false);
+        }
+        if job.state == JobState::Stopped {
+            return true;
+        }
+        job.update(true);
+        match job.state {
+            JobState::Done(code) => {
+                result.status = code;
+                self.jobs.remove(id);
+                true
+            }
+            JobState::Stopped => {
+                #[cfg(unix)]
+                {
+                    result.status = self.report_stopped(id);
+                }
+                false
+            }
+            JobState::Running => true,
+        }
+    }
+
+    fn run_disown(&mut self, args: &[String], result: &mut CommandResult) {
+        let ids = if args.first().is_some_and(|arg| arg == "-a") {
+            Ok(self.jobs.jobs.iter().map(|job| job.id).collect())
+        } else if args.is_empty() {
+            self.jobs.resolve(None).map(|id| vec![id])
+        } else {
+            args.iter()
+                .map(|arg| self.jobs.resolve(Some(arg)))
+                .collect::<Result<Vec<usize>, String>>()
+        };
+
+        match ids {
+            Ok(ids) => {
+                for id in ids {
+                    self.jobs.remove(id);
+                }
+            }
+            Err(message) => {
+                result.stderr = format!("disown: {message}\n").into_bytes();
+                result.status = 1;
+            }
+        }
+    }
+
+    fn run_trap(&mut self, args: &[String], result: &mut CommandResult) {
+        let mut args = args;
+        match args.first().map(String::as_str) {
+            Some("-l") => {
+                result.stdout = signal_list().into_bytes();
+                return;
+            }
+            Some("-p") => {
+                args = &args[1..];
+                if !args.is_empty() {
+                    result.stdout = self.list_traps(args, result).into_bytes();
+                    return;
+                }
+            }
+            Some("--") => args = &args[1..],
+            Some(flag) if flag.len() > 1 && flag.starts_with('-') => {
+                result.stderr = format!(
+                    "trap: {flag}: invalid option\ntrap: usage: trap [-lp] [[arg] signal_spec ...]\n"
+                )
+                .into_bytes();
+                result.status = 2;
+                return;
+            }
+            _ => {}
+        }
+
+        if args.is_empty() {
+            let all: Vec<String> = PSEUDO_SIGNALS[..1]
+                .iter()
+                .chain(SIGNALS.iter().map(|(name, _)| name))
+                .chain(&PSEUDO_SIGNALS[1..])
+                .filter(|name| self.traps.contains_key(*name))
+                .map(|name| name.to_string())
+                .collect();
+            result.stdout = self.list_traps(&all, result).into_bytes();
+            return;
+        }
+
+        // A lone condition, `-`, or a leading signal number resets the
+        // conditions to their defaults.
+        let (action, specs) = if args[0] == "-" {
+            (None, &args[1..])
+        } else if args.len() == 1 || args[0].parse::<u32>().is_ok() {
+            (None, args)
+        } else {
+            (Some(args[0].as_str()), &args[1..])
+        };
+
+        for spec in specs {
+            let Some(condition) = trap_condition(spec) else {
+                result
+                    .stderr
+                    .extend(format!("trap: {spec}: invalid signal specification\n").bytes());
+                result.status = 1;
+                continue;
+            };
+            match action {
+                Some(action) => {
+                    self.traps.insert(condition, action.to_string());
+                }
+                None => {
+                    self.traps.remove(condition);
+                }
+            }
+            #[cfg(unix)]
+            if let Some((_, signal)) = find_signal(condition) {
+                self.set_signal_disposition(signal, action);
             }
-            continue;
+        }
+    }
+
+    /// `source FILE [args]`: runs FILE in this shell, with `args` as the
+    /// positional parameters while it runs.
+    fn run_source(&mut self, cmd: &str, args: &[String], result: &mut CommandResult) {
+        let Some(name) = args.first() else {
+            result.stderr = format!(
+                "{cmd}: filename argument required\n{cmd}: usage: {cmd} filename [arguments]\n"
+            )
+            .into_bytes();
+            result.status = 2;
+            return;
+        };
+
+        let path = find_source_file(name, self.variable("PATH"));
+        let saved =
+            (args.len() > 1).then(|| std::mem::replace(&mut self.positional, args[1..].to_vec()));
+        self.last_status = 0;
+        let outcome = self.execute_file(&path);
+        if let Some(positional) = saved {
+            self.positional = positional;
         }
 
-        if let Some(result) = run_builtin(&cmd, &args, true, true, &history) {
-            if !result.stdout.is_empty() {
-                write_bytes_output(&result.stdout, OutputStream::Stdout, &redirects);
+        match outcome {
+            Ok(()) => {
+                if !self.exit_requested {
+                    self.execute_trap("RETURN");
+                }
+                result.status = self.last_status;
             }
-            if !result.stderr.is_empty() {
-                write_bytes_output(&result.stderr, OutputStream::Stderr, &redirects);
+            Err(err) => {
+                result.stderr = format!("{cmd}: {name}: {}\n", io_error_message(&err)).into_bytes();
+                result.status = 1;
             }
-            if result.should_exit {
+        }
+    }
+
+    fn run_hash(&mut self, args: &[String], result: &mut CommandResult) {
+        let mut reset = false;
+        let mut delete = false;
+        let mut print = false;
+        let mut pathname = None;
+        let mut names = args;
+        while let Some(flag) = names
+            .first()
+            .filter(|arg| arg.len() > 1 && arg.starts_with('-'))
+        {
+            names = &names[1..];
+            if flag == "--" {
                 break;
             }
-            continue;
+            for ch in flag.chars().skip(1) {
+                match ch {
+                    'r' => reset = true,
+                    'd' => delete = true,
+                    't' => print = true,
+                    'p' if !names.is_empty() => {
+                        pathname = Some(PathBuf::from(&names[0]));
+                        names = &names[1..];
+                    }
+                    'p' => {
+                        result.stderr = b"hash: -p: option requires an argument\n".to_vec();
+                        result.status = 2;
+                        return;
+                    }
+                    _ => {
+                        result.stderr = format!(
+                            "hash: -{ch}: invalid option\nhash: usage: hash [-r] [-p pathname] [-dt] [name ...]\n"
+                        )
+                        .into_bytes();
+                        result.status = 2;
+                        return;
+                    }
+                }
+            }
+        }
+
+        let table = self.command_table();
+        if reset {
+            table.forget();
+        }
+        if names.is_empty() {
+            if print || delete || pathname.is_some() {
+                result.stderr = format!(
+                    "hash: -{}: option requires an argument\n",
+                    if print {
+                        't'
+                    } else if delete {
+                        'd'
+                    } else {
+                        'p'
+                    }
+                )
+                .into_bytes();
+                result.status = 1;
+            } else if !reset {
+                result.stdout = if table.hashed.is_empty() {
+                    b"hash: hash table empty\n".to_vec()
+                } else {
+                    let mut out = String::from("hits\tcommand\n");
+                    for (path, hits) in table.hashed.values() {
+                        out.push_str(&format!("{hits:4}\t{}\n", path.display()));
+                    }
+                    out.into_bytes()
+                };
+            }
+            return;
+        }
+
+        for name in names {
+            let found = if let Some(path) = &pathname {
+                table.hashed.insert(name.clone(), (path.clone(), 0));
+                true
+            } else if delete {
+                table.hashed.remove(name).is_some()
+            } else if print {
+                match table.hashed.get(name) {
+                    Some((path, _)) if names.len() == 1 => {
+                        result
+                            .stdout
+                            .extend(format!("{}\n", path.display()).bytes());
+                        true
+                    }
+                    Some((path, _)) => {
+                        result
+                            .stdout
+                            .extend(format!("{name}\t{}\n", path.display()).bytes());
+                        true
+                    }
+                    None => false,
+                }
+            } else if is_builtin_command(name) || name.contains('/') {
+                true
+            } else {
+                let path = find_in_path(name, table.path.as_deref());
+                path.map(|path| table.hashed.insert(name.clone(), (path, 0)))
+                    .is_some()
+            };
+            if !found {
+                result
+                    .stderr
+                    .extend(format!("hash: {name}: not found\n").bytes());
+                result.status = 1;
+            }
+        }
+    }
+
+    fn run_kill(&mut self, args: &[String], result: &mut CommandResult) {
+        const USAGE: &str = "kill: usage: kill [-s sigspec | -n signum | -sigspec] pid | jobspec ... or kill -l [sigspec]\n";
+
+        let mut signal = libc::SIGTERM;
+        let mut targets = args;
+        match args.first().map(String::as_str) {
+            Some("-l" | "-L") => return self.list_signals(&args[1..], result),
+            Some("-s" | "-n") => {
+                let Some(spec) = args.get(1) else {
+                    result.stderr =
+                        format!("kill: {}: option requires an argument\n{USAGE}", args[0])
+                            .into_bytes();
+                    result.status = 2;
+                    return;
+                };
+                match kill_signal(spec) {
+                    Some(number) => signal = number,
+                    None => {
+                        result.stderr =
+                            format!("kill: {spec}: invalid signal specification\n").into_bytes();
+                        result.status = 1;
+                        return;
+                    }
+                }
+                targets = &args[2..];
+            }
+            Some("--") => targets = &args[1..],
+            Some(flag) if flag.len() > 1 && flag.starts_with('-') => {
+                match kill_signal(&flag[1..]) {
+                    Some(number) => signal = number,
+                    None => {
+                        result.stderr =
+                            format!("kill: {}: invalid signal specification\n", &flag[1..])
+                                .into_bytes();
+                        result.status = 1;
+                        return;
+                    }
+                }
+                targets = &args[1..];
+            }
+            _ => {}
+        }
+
+        if targets.is_empty() {
+            result.stderr = USAGE.as_bytes().to_vec();
+            result.status = 2;
+            return;
         }
 
-        if let Some(_path) = find_in_path(&cmd) {
-            let mut command = Command::new(&cmd);
-            command.args(&args);
+        for target in targets {
+            if let Err(message) = self.signal_target(target, signal) {
+                result.stderr.extend(format!("kill: {message}\n").bytes());
+                result.status = 1;
+            }
+        }
+    }
 
-            if let Some((path, mode)) = &redirects.stdout {
-                if let Ok(file) = open_redirect_file(path, *mode) {
-                    command.stdout(Stdio::from(file));
+    /// Delivers `signal` to a pid or to every process of a job. Jobs with a
+    /// process group of their own are signalled as a group.
+    fn signal_target(&mut self, target: &str, signal: i32) -> Result<(), String> {
+        let (pid, group) = if target.starts_with('%') {
+            let id = self.jobs.resolve(Some(target))?;
+            let Some(job) = self.jobs.get_mut(id) else {
+                return Err(format!("{target}: no such job"));
+            };
+            #[cfg(unix)]
+            let own_group = self.job_control.is_some();
+            #[cfg(not(unix))]
+            let own_group = false;
+            if !own_group {
+                for (pid, _) in &job.processes {
+                    send_signal(*pid, signal, false)
+                        .map_err(|err| format!("({pid}) - {}", io_error_message(&err)))?;
                 }
+                return Ok(());
             }
+            (job.pgid, true)
+        } else {
+            let pid = target
+                .parse::<i32>()
+                .map_err(|_| format!("{target}: arguments must be process or job IDs"))?;
+            (pid, false)
+        };
+        send_signal(pid, signal, group)
+            .map_err(|err| format!("({pid}) - {}", io_error_message(&err)))
+    }
 
-            if let Some((path, mode)) = &redirects.stderr {
-                if let Ok(file) = open_redirect_file(path, *mode) {
-                    command.stderr(Stdio::from(file));
+    /// `kill -l`: lists every signal, or translates each argument between a
+    /// signal name and its number. Exit statuses above 128 name the signal
+    /// that ended a command.
+    fn list_signals(&self, args: &[String], result: &mut CommandResult) {
+        if args.is_empty() {
+            result.stdout = signal_list().into_bytes();
+            return;
+        }
+        for arg in args {
+            let line = match arg.parse::<i32>() {
+                Ok(number) => {
+                    let number = if number > 128 { number - 128 } else { number };
+                    SIGNALS
+                        .into_iter()
+                        .find(|(_, signal)| *signal == number)
+                        .map(|(name, _)| name.to_string())
+                }
+                Err(_) => find_signal(arg).map(|(_, signal)| signal.to_string()),
+            };
+            match line {
+                Some(line) => result.stdout.extend(format!("{line}\n").bytes()),
+                None => {
+                    result
+                        .stderr
+                        .extend(format!("kill: {arg}: invalid signal specification\n").bytes());
+                    result.status = 1;
                 }
             }
+        }
+    }
 
-            let status = command.status();
-            if status.is_err() {
-                write_output(
-                    &format!("{cmd}: command not found\n"),
-                    OutputStream::Stdout,
-                    &redirects,
-                );
+    /// Formats `trap` commands that would restore the actions for `specs`.
+    fn list_traps(&self, specs: &[String], result: &mut CommandResult) -> String {
+        let mut out = String::new();
+        for spec in specs {
+            let Some(condition) = trap_condition(spec) else {
+                result
+                    .stderr
+                    .extend(format!("trap: {spec}: invalid signal specification\n").bytes());
+                result.status = 1;
+                continue;
+            };
+            let Some(action) = self.traps.get(condition) else {
+                continue;
+            };
+            let name = match find_signal(condition) {
+                Some(_) => format!("SIG{condition}"),
+                None => condition.to_string(),
+            };
+            out.push_str(&format!("trap -- {} {name}\n", single_quote(action)));
+        }
+        out
+    }
+
+    /// Installs the disposition for a trap action: `None` restores the
+    /// default, an empty action ignores the signal and anything else records
+    /// it for `run_pending_traps`.
+    #[cfg(unix)]
+    fn set_signal_disposition(&self, signal: i32, action: Option<&str>) {
+        let handler = match action {
+            None if self.job_control.is_some() && JOB_CONTROL_SIGNALS.contains(&signal) => {
+                libc::SIG_IGN
+            }
+            None => libc::SIG_DFL,
+            Some("") => libc::SIG_IGN,
+            Some(_) => record_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        };
+        // SAFETY: `record_signal` only performs an atomic store. Signals that
+        // cannot be caught make this fail harmlessly.
+        unsafe {
+            libc::signal(signal, handler);
+        }
+    }
+
+    fn save_history(&self) {
+        if let Some(path) = self.histfile.as_ref() {
+            if should_append_history_on_exit() {
+                let _ = append_history_file(path, &self.history, self.history_last_persisted);
+            } else {
+                let _ = write_history_file(path, &self.history);
+            }
+        }
+    }
+}
+
+/// Where a shell's commands come from.
+#[derive(Default)]
+enum ScriptSource {
+    #[default]
+    Stdin,
+    Command(String),
+    File(PathBuf),
+}
+
+/// The shell's own command line: `-c string [name [args...]]`,
+/// `-s [args...]` or `script [args...]`, after the startup options.
+#[derive(Default)]
+struct Invocation {
+    source: ScriptSource,
+    arg0: Option<String>,
+    positional: Vec<String>,
+    login: bool,
+    norc: bool,
+    noprofile: bool,
+    rcfile: Option<PathBuf>,
+}
+
+/// Parses the full argument list; a program name starting with `-` marks a
+/// login shell.
+fn parse_invocation(args: Vec<String>) -> Result<Invocation, String> {
+    let mut invocation = Invocation {
+        login: args.first().is_some_and(|name| name.starts_with('-')),
+        ..Invocation::default()
+    };
+    let mut command = false;
+    let mut read_stdin = false;
+    let mut idx = 1;
+    while let Some(arg) = args.get(idx) {
+        idx += 1;
+        if arg == "--" || arg == "-" {
+            break;
+        }
+        if arg.starts_with("--") {
+            match arg.as_str() {
+                "--login" => invocation.login = true,
+                "--norc" => invocation.norc = true,
+                "--noprofile" => invocation.noprofile = true,
+                "--rcfile" => {
+                    let file = args
+                        .get(idx)
+                        .ok_or_else(|| "--rcfile: option requires an argument".to_string())?;
+                    invocation.rcfile = Some(PathBuf::from(file));
+                    idx += 1;
+                }
+                _ => return Err(format!("{arg}: invalid option")),
             }
             continue;
         }
+        let Some(flags) = arg.strip_prefix('-').filter(|flags| !flags.is_empty()) else {
+            idx -= 1;
+            break;
+        };
+        for flag in flags.chars() {
+            match flag {
+                'c' => command = true,
+                's' => read_stdin = true,
+                'l' => invocation.login = true,
+                _ => return Err(format!("-{flag}: invalid option")),
+            }
+        }
+    }
 
-        write_output(
-            &format!("{cmd}: command not found\n"),
-            OutputStream::Stdout,
-            &redirects,
-        );
+    let mut rest = args.into_iter().skip(idx);
+    let (source, arg0) = if command {
+        let text = rest
+            .next()
+            .ok_or_else(|| "-c: option requires an argument".to_string())?;
+        (ScriptSource::Command(text), rest.next())
+    } else if read_stdin {
+        (ScriptSource::Stdin, None)
+    } else {
+        match rest.next() {
+            Some(script) => (ScriptSource::File(PathBuf::from(&script)), Some(script)),
+            None => (ScriptSource::Stdin, None),
+        }
+    };
+    invocation.source = source;
+    invocation.arg0 = arg0;
+    invocation.positional = rest.collect();
+    Ok(invocation)
+}
+
+/// Yields the lines of a script read from standard input a byte at a time,
+/// leaving whatever follows the current line for the commands it runs.
+#[cfg(unix)]
+fn stdin_lines() -> impl Iterator<Item = String> {
+    // SAFETY: standard input stays open for the life of the shell, and
+    // `ManuallyDrop` keeps this handle from closing it.
+    let mut stdin = std::mem::ManuallyDrop::new(unsafe { fs::File::from_raw_fd(STDIN_FILENO) });
+    std::iter::from_fn(move || {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        loop {
+            match stdin.read(&mut byte) {
+                Ok(0) => break,
+                Ok(_) if byte[0] == b'\n' => break,
+                Ok(_) => line.push(byte[0]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return None,
+            }
+        }
+        if byte[0] != b'\n' && line.is_empty() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&line).into_owned())
+    })
+}
+
+#[cfg(not(unix))]
+fn stdin_lines() -> impl Iterator<Item = String> {
+    io::stdin().lines().map_while(Result::ok)
+}
+
+/// Reads and runs commands from the terminal until EOF or `exit`.
+fn run_interactive(shell: &mut Shell) {
+    #[cfg(unix)]
+    let raw_mode = RawModeGuard::new(STDIN_FILENO).ok();
+    #[cfg(unix)]
+    {
+        shell.job_control = raw_mode.as_ref().map(JobControl::new);
     }
 
-    if let Some(path) = histfile.as_ref() {
-        if should_append_history_on_exit() {
-            let _ = append_history_file(path, &history, history_last_persisted);
-        } else {
-            let _ = write_history_file(path, &history);
+    loop {
+        shell.notify_jobs();
+        print!("{PROMPT}");
+        io::stdout().flush().unwrap();
+
+        shell.refresh_command_table();
+        let input = match read_user_input(&shell.history, &mut shell.commands) {
+            Ok(Some(input)) => input,
+            Ok(None) => break, // EOF
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                shell.last_status = 130;
+                continue;
+            }
+            Err(err) => panic!("failed to read input: {err}"),
+        };
+
+        let mut commands = CommandTable::default();
+        commands.set_path(shell.variable("PATH"));
+        shell.execute_line(&input, &mut || {
+            print!("{CONTINUATION_PROMPT}");
+            let _ = io::stdout().flush();
+            read_user_input(&[], &mut commands)
+        });
+        if shell.exit_requested {
+            break;
         }
     }
 }
+
+fn main() {
+    let invocation = match parse_invocation(env::args().collect()) {
+        Ok(invocation) => invocation,
+        Err(message) => {
+            report_error(&message);
+            std::process::exit(2);
+        }
+    };
+    let mut shell = Shell::new();
+    if let Some(arg0) = invocation.arg0.clone() {
+        shell.arg0 = arg0;
+    }
+    shell.positional = invocation.positional.clone();
+
+    let interactive = matches!(invocation.source, ScriptSource::Stdin) && io::stdin().is_terminal();
+    shell.interactive = interactive;
+    shell.run_startup_files(&invocation, interactive);
+    if shell.exit_requested {
+        shell.run_exit_trap();
+        std::process::exit(shell.last_status);
+    }
+
+    match invocation.source {
+        ScriptSource::Command(text) => shell.execute_source(&text),
+        ScriptSource::File(path) => {
+            if let Err(err) = shell.execute_file(&path) {
+                report_error(&format!("{}: {}", path.display(), io_error_message(&err)));
+                shell.last_status = if err.kind() == io::ErrorKind::NotFound {
+                    STATUS_NOT_FOUND
+                } else {
+                    STATUS_NOT_EXECUTABLE
+                };
+            }
+        }
+        ScriptSource::Stdin if interactive => run_interactive(&mut shell),
+        ScriptSource::Stdin => shell.execute_lines(&mut stdin_lines()),
+    }
+
+    shell.run_exit_trap();
+    if interactive {
+        shell.save_history();
+    }
+    std::process::exit(shell.last_status);
+}